@@ -0,0 +1,278 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Context, Error, Result};
+use clap::ValueEnum;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use wireguard_keys::Pubkey;
+
+/// How often nodes record that they're still using their address
+pub const ADDRESS_USAGE_REFRESH_PERIOD: Duration = Duration::from_secs(5 * 60);
+
+/// How to pick the address of a new node
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+pub enum AllocationStrategy {
+    /// The lowest free address
+    LowestFree,
+
+    /// A random free address
+    Random,
+
+    /// An address derived from the public key, or the next free one after it
+    PublicKeyHash,
+
+    /// An address derived from the hostname, or the next free one after it
+    ///
+    /// Falls back to the public key if there is no hostname.
+    HostnameHash,
+
+    /// The address that wasn't used for the longest time, preferring ones that were never used
+    LeastRecentlyUsed,
+}
+
+impl AllocationStrategy {
+    /// The strategy to use if none was configured
+    ///
+    /// IPv6 networks are usually far too large to search from the start, so nodes derive the
+    /// interface identifier of their address from their public key there. This keeps addresses
+    /// stable per key and only needs to look further on collisions.
+    pub fn for_network(network: &IpNet) -> Self {
+        match network {
+            IpNet::V4(_) => AllocationStrategy::LowestFree,
+            IpNet::V6(_) => AllocationStrategy::PublicKeyHash,
+        }
+    }
+}
+
+/// # When a node last used its address
+///
+/// Nodes record this in Consul periodically so that addresses of nodes that are gone can be kept
+/// in quarantine for a while before they're handed out again.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct AddressUsage {
+    pub public_key: Pubkey,
+    pub address: IpAddr,
+
+    /// In RFC 3339 format.
+    pub last_seen: String,
+}
+
+impl AddressUsage {
+    pub fn new(public_key: Pubkey, address: IpAddr) -> Self {
+        Self {
+            public_key,
+            address,
+            last_seen: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        }
+    }
+}
+
+/// # Addresses that are never handed out to new nodes
+///
+/// Written either as a network like `10.0.0.0/28` or as an inclusive range like
+/// `10.0.0.1-10.0.0.10`. Reserved addresses can still be assigned explicitly via `--address`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct AddressRange {
+    pub start: IpAddr,
+    pub end: IpAddr,
+}
+
+impl AddressRange {
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        address.is_ipv4() == self.start.is_ipv4()
+            && (to_u128(self.start)..=to_u128(self.end)).contains(&to_u128(*address))
+    }
+}
+
+impl FromStr for AddressRange {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Ok(network) = s.parse::<IpNet>() {
+            return Ok(Self {
+                start: network.network(),
+                end: network.broadcast(),
+            });
+        }
+        let (start, end) = s.split_once('-').unwrap_or((s, s));
+        let start: IpAddr = start
+            .trim()
+            .parse()
+            .context(format!("Invalid address range {s:?}"))?;
+        let end: IpAddr = end
+            .trim()
+            .parse()
+            .context(format!("Invalid address range {s:?}"))?;
+        if start.is_ipv4() != end.is_ipv4() || start > end {
+            bail!("Invalid address range {s:?}");
+        }
+        Ok(Self { start, end })
+    }
+}
+
+impl TryFrom<String> for AddressRange {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for AddressRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+impl From<AddressRange> for String {
+    fn from(value: AddressRange) -> Self {
+        value.to_string()
+    }
+}
+
+/// Picks addresses for new nodes
+pub struct AddressAllocator {
+    pub strategy: AllocationStrategy,
+
+    /// How long an address can't be handed out again after it was last used.
+    pub quarantine: Duration,
+
+    /// Addresses of the current peers.
+    pub occupied: HashSet<IpAddr>,
+
+    /// When addresses were last used.
+    pub usage: Vec<AddressUsage>,
+
+    /// Addresses that are never handed out.
+    pub reserved: Vec<AddressRange>,
+}
+
+impl AddressAllocator {
+    /// Pick an address in the network for the node with the given public key and hostname
+    ///
+    /// Returns `None` if there are no free addresses.
+    pub fn allocate(
+        &self,
+        network: &IpNet,
+        public_key: &Pubkey,
+        hostname: Option<&str>,
+    ) -> Option<IpAddr> {
+        let mut hosts = network.hosts();
+        let mut first = to_u128(hosts.next()?);
+        let last = hosts.next_back().map_or(first, to_u128);
+
+        // Unlike in IPv4, the hosts of IPv6 networks include the network address which is the
+        // Subnet-Router anycast address (RFC 4291) and shouldn't be given to a node. Point-to-point
+        // links don't have one (RFC 6164).
+        if matches!(network, IpNet::V6(x) if x.prefix_len() < 127) {
+            first += 1;
+        }
+        let size = (last - first).checked_add(1);
+
+        // Walk the hosts starting at an offset depending on the strategy, wrapping around at the
+        // end of the network.
+        let offset = match self.strategy {
+            AllocationStrategy::LowestFree | AllocationStrategy::LeastRecentlyUsed => 0,
+            AllocationStrategy::Random => rand::random(),
+            AllocationStrategy::PublicKeyHash => hash(&public_key[..]),
+            AllocationStrategy::HostnameHash => match hostname {
+                Some(hostname) => hash(hostname.as_bytes()),
+                None => hash(&public_key[..]),
+            },
+        };
+        let mut i = 0;
+        let mut candidates = std::iter::from_fn(|| {
+            while i <= last - first {
+                let index = match size {
+                    Some(size) => offset.wrapping_add(i) % size,
+                    None => offset.wrapping_add(i),
+                };
+                let address = from_u128(network, first + index);
+
+                // Reserved ranges might cover a large part of an IPv6 network, so we jump past
+                // them instead of visiting each of their addresses.
+                match self.reserved.iter().find(|x| x.contains(&address)) {
+                    Some(range) => {
+                        let end = to_u128(range.end).min(last);
+                        i = i.saturating_add(end - (first + index) + 1);
+                    }
+                    None => {
+                        i += 1;
+                        return Some(address);
+                    }
+                }
+            }
+            None
+        });
+
+        let last_seen = self.last_seen();
+        let now = SystemTime::now();
+        let available = |address: &IpAddr| {
+            !self.occupied.contains(address)
+                && !self.reserved.iter().any(|x| x.contains(address))
+                && last_seen.get(address).is_none_or(|last_seen| {
+                    now.duration_since(*last_seen)
+                        .is_ok_and(|x| x >= self.quarantine)
+                })
+        };
+
+        if self.strategy != AllocationStrategy::LeastRecentlyUsed {
+            return candidates.find(available);
+        }
+        candidates
+            .find(|x| !last_seen.contains_key(x) && available(x))
+            .or_else(|| {
+                last_seen
+                    .iter()
+                    .filter(|(address, _)| network.contains(*address) && available(address))
+                    .min_by_key(|(address, last_seen)| (**last_seen, **address))
+                    .map(|(address, _)| *address)
+            })
+    }
+
+    /// The latest time each address was used
+    fn last_seen(&self) -> HashMap<IpAddr, SystemTime> {
+        let mut last_seen = HashMap::new();
+        for usage in &self.usage {
+            let Ok(time) = humantime::parse_rfc3339(&usage.last_seen) else {
+                continue;
+            };
+            last_seen
+                .entry(usage.address)
+                .and_modify(|x: &mut SystemTime| *x = (*x).max(time))
+                .or_insert(time);
+        }
+        last_seen
+    }
+}
+
+fn hash(data: &[u8]) -> u128 {
+    let digest = Sha256::digest(data);
+    u128::from_be_bytes(digest[..16].try_into().expect("Digest is long enough"))
+}
+
+fn to_u128(address: IpAddr) -> u128 {
+    match address {
+        IpAddr::V4(address) => u32::from(address).into(),
+        IpAddr::V6(address) => address.into(),
+    }
+}
+
+fn from_u128(network: &IpNet, value: u128) -> IpAddr {
+    match network {
+        IpNet::V4(_) => IpAddr::V4(Ipv4Addr::from(value as u32)),
+        IpNet::V6(_) => IpAddr::V6(Ipv6Addr::from(value)),
+    }
+}
@@ -1,9 +1,20 @@
 use std::{net::IpAddr, path::PathBuf, time::Duration};
 
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
 use ipnet::IpNet;
 use pnet::datalink::{self, NetworkInterface};
 use reqwest::Url;
+use wireguard_keys::Pubkey;
+use wiresmith::{
+    allocation::{AddressRange, AllocationStrategy},
+    endpoint::{EndpointCandidate, IpFamily},
+    lease::LeaseIdentity,
+    metadata::NodeMetadata,
+    networkd::LinkOptions,
+    policy::Tags,
+    topology::{NodeRole, Topology},
+    wireguard::KeepalivePolicy,
+};
 
 #[derive(Copy, Clone, ValueEnum)]
 pub enum NetworkBackend {
@@ -11,21 +22,207 @@ pub enum NetworkBackend {
     // Wgquick
 }
 
+#[derive(Subcommand)]
+pub enum Command {
+    /// Create a join token for enrolling a new node and print its secret
+    ///
+    /// With --authority-secret-key, the token is signed by the mesh authority as nodes started
+    /// with --authority-public-key require.
+    CreateJoinToken {
+        /// How long the token can be used for
+        #[arg(long, default_value = "1h", value_parser = humantime::parse_duration)]
+        ttl: Duration,
+
+        /// Address the node using the token gets
+        ///
+        /// If not provided, the node allocates an available address as usual.
+        #[arg(long)]
+        address: Option<IpAddr>,
+    },
+
+    /// List the nodes waiting for approval
+    Pending,
+
+    /// Approve a pending node so that other nodes peer with it
+    Approve {
+        /// Public key of the node
+        public_key: Pubkey,
+    },
+
+    /// Reject a pending node
+    ///
+    /// Also revokes nodes that were approved or enrolled via a join token before.
+    Reject {
+        /// Public key of the node
+        public_key: Pubkey,
+    },
+
+    /// Register a roaming device and print a wg-quick config for it
+    ///
+    /// The config can be used with the stock WireGuard apps. A QR code of it is printed to stderr
+    /// so that the config itself can be redirected to a file.
+    ExportClient {
+        /// Name of the device
+        #[arg(long)]
+        name: String,
+
+        /// Address of the device
+        ///
+        /// If not provided, an available address from --network is allocated.
+        #[arg(short, long)]
+        address: Option<IpAddr>,
+
+        /// Network of the mesh
+        #[arg(short, long)]
+        network: IpNet,
+
+        /// Only peer with this hub and route the whole network via it
+        ///
+        /// If not provided, the device peers with all nodes of the mesh. Otherwise, the other nodes
+        /// route the address of the device via the hub, which needs IP forwarding enabled.
+        #[arg(long)]
+        hub: Option<Pubkey>,
+
+        /// Persistent keepalive the device should use
+        ///
+        /// Set to 0 in order to disable.
+        #[arg(short = 'k', long, default_value = "25s", value_parser = keep_alive)]
+        keepalive: u64,
+    },
+
+    /// Print the public key of the mesh authority
+    ///
+    /// Nodes are given this key via --authority-public-key. Requires --authority-secret-key.
+    AuthorityPublicKey,
+
+    /// Sign the enrolment of a node with the mesh authority and print it
+    ///
+    /// The node is started with the enrolment via --enrolment. Requires --authority-secret-key.
+    SignEnrolment {
+        /// Public key of the node
+        #[arg(long)]
+        public_key: Pubkey,
+
+        /// Address of the node
+        #[arg(short, long)]
+        address: IpAddr,
+    },
+
+    /// Manage peers that don't run wiresmith themselves
+    Peer {
+        #[command(subcommand)]
+        command: PeerCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum PeerCommand {
+    /// Register a peer in Consul so that all nodes peer with it
+    Add {
+        /// Public key of the peer
+        #[arg(long)]
+        public_key: Pubkey,
+
+        /// Endpoint of the peer
+        ///
+        /// Can be a hostname or IP address with an optional port which defaults to --wg-port.
+        #[arg(long)]
+        endpoint: String,
+
+        /// WireGuard UDP port of the peer if --endpoint doesn't include one
+        #[arg(short = 'p', long, default_value = "51820")]
+        wg_port: u16,
+
+        /// Address of the peer
+        ///
+        /// If not provided, an available address from --network is allocated.
+        #[arg(short, long, required_unless_present = "network")]
+        address: Option<IpAddr>,
+
+        /// Network to allocate the address from
+        #[arg(short, long)]
+        network: Option<IpNet>,
+
+        /// Persistent keepalive the tunnels to the peer should use
+        #[arg(short = 'k', long, value_parser = keep_alive)]
+        keepalive: Option<u64>,
+    },
+
+    /// Remove a peer registered via `wiresmith peer add`
+    Remove {
+        /// Public key of the peer
+        public_key: Pubkey,
+    },
+}
+
 #[derive(Parser)]
-#[command(name = "wiresmith", author, about, version)]
+#[command(
+    name = "wiresmith",
+    author,
+    about,
+    version,
+    subcommand_negates_reqs = true
+)]
 pub struct CliArgs {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// Consul backend socket address
-    #[arg(long, default_value = "http://127.0.0.1:8500")]
+    #[arg(long, global = true, default_value = "http://127.0.0.1:8500")]
     pub consul_address: Url,
 
     /// Consul secret token
-    #[arg(long)]
+    #[arg(long, global = true)]
     pub consul_token: Option<String>,
 
     /// Consul KV prefix
-    #[arg(long, default_value = "wiresmith")]
+    #[arg(long, global = true, default_value = "wiresmith")]
     pub consul_prefix: String,
 
+    /// File containing the public key of the mesh authority
+    ///
+    /// If provided, peers without an enrolment signed by the authority for their public key and
+    /// address are rejected. The key is printed by `wiresmith authority-public-key`.
+    #[arg(long, global = true)]
+    pub authority_public_key: Option<PathBuf>,
+
+    /// File containing the secret key of the mesh authority
+    ///
+    /// This is only used by the subcommands signing enrolments, including those of the peers
+    /// registered via `wiresmith peer add` and `wiresmith export-client`, join tokens and
+    /// approvals, and shouldn't be given to nodes. The file contains a base64 encoded 32 byte Ed25519 secret key which can be
+    /// generated with `head -c 32 /dev/urandom | base64`.
+    #[arg(long, global = true)]
+    pub authority_secret_key: Option<PathBuf>,
+
+    /// File containing the enrolment of this node signed by the mesh authority
+    ///
+    /// The enrolment is created with `wiresmith sign-enrolment` for the public key and address of
+    /// this node and published along with our config.
+    #[arg(long)]
+    pub enrolment: Option<PathBuf>,
+
+    /// Join token to enrol this node with
+    ///
+    /// The token is created with `wiresmith create-join-token` and claimed on startup. If the
+    /// token comes with an address, it's used unless --address is provided.
+    #[arg(long)]
+    pub join_token: Option<String>,
+
+    /// Only peer with nodes that were enrolled via a join token
+    ///
+    /// Must be used by all nodes of a mesh, which in turn all need a join token.
+    #[arg(long)]
+    pub require_enrolment: bool,
+
+    /// Wait for an operator to approve new nodes
+    ///
+    /// Nodes that weren't enrolled via a join token publish their config as pending until they're
+    /// approved with `wiresmith approve`. Must be used by all nodes of a mesh as only approved or
+    /// enrolled nodes are peered with.
+    #[arg(long)]
+    pub require_approval: bool,
+
     /// Update period - how often to check for peer updates
     #[arg(short, long, default_value = "10s", value_parser = humantime::parse_duration)]
     pub update_period: Duration,
@@ -44,27 +241,171 @@ pub struct CliArgs {
     #[arg(short = 'k', long, default_value = "25s", value_parser = keep_alive)]
     pub keepalive: u64,
 
+    /// Persistent keepalive that tunnels to this node should use
+    ///
+    /// This is published for other peers to pick up. Nodes behind NAT need a keepalive to stay
+    /// reachable whereas publicly reachable nodes can set this to 0 to signal that they don't
+    /// need one. If not provided, --keepalive is used for the tunnels to this node.
+    #[arg(long, value_parser = keep_alive)]
+    pub advertise_keepalive: Option<u64>,
+
+    /// MTU of the WireGuard interface
+    ///
+    /// If not provided, an existing value in the config is kept and new configs use 1280.
+    #[arg(long)]
+    pub mtu: Option<u32>,
+
+    /// Automatically discover the path MTU to each peer
+    ///
+    /// The MTU of the interface is set to the largest value that is safe to use with all peers.
+    /// --mtu is used as the upper bound. Peers that can't be probed, e.g. because ICMP is
+    /// filtered, restrict the MTU to the one they published. If they didn't publish one, the
+    /// current MTU is kept.
+    #[arg(long)]
+    pub pmtu_discovery: bool,
+
+    /// How often to probe the path MTU to each peer again
+    #[arg(long, default_value = "10min", value_parser = humantime::parse_duration)]
+    pub pmtu_probe_period: Duration,
+
+    /// Firewall mark to set on outgoing WireGuard packets
+    #[arg(long)]
+    pub fwmark: Option<u32>,
+
+    /// Routing table to add the routes to the peers to
+    ///
+    /// Can be a table number or a name defined in networkd.conf. Use "off" to not add any routes.
+    #[arg(long)]
+    pub route_table: Option<String>,
+
+    /// Metric of the routes to the peers
+    #[arg(long)]
+    pub route_metric: Option<u32>,
+
+    /// How nodes peer with each other
+    ///
+    /// Must be the same for all nodes. In the hub-and-spoke topology, spokes only peer with hubs
+    /// and route the whole --network via them while hubs peer with everyone.
+    #[arg(long, value_enum, default_value_t)]
+    pub topology: Topology,
+
+    /// Role of this node in the hub-and-spoke topology
+    ///
+    /// Hubs need to have IP forwarding enabled so that spokes can reach each other.
+    #[arg(long, required_if_eq("topology", "hub-and-spoke"))]
+    pub role: Option<NodeRole>,
+
+    /// Metadata label to publish for this node in the form of KEY=VALUE
+    ///
+    /// This is purely informational and published along with the hostname, OS, wiresmith version
+    /// and start time of the node. Can be provided multiple times.
+    #[arg(long = "meta", value_parser = key_value)]
+    pub meta: Vec<(String, String)>,
+
+    /// Tag to publish for this node in the form of KEY=VALUE
+    ///
+    /// Tags are used by the peering policy to decide who may peer with whom. Can be provided
+    /// multiple times.
+    #[arg(long = "tag", value_parser = key_value)]
+    pub tags: Vec<(String, String)>,
+
+    /// JSON file containing the peering policy
+    ///
+    /// If not provided, the policy is read from the "policy" key under --consul-prefix. Without a
+    /// policy, all nodes peer with each other.
+    #[arg(long)]
+    pub policy_file: Option<PathBuf>,
+
+    /// JSON file containing peers that don't run wiresmith
+    ///
+    /// These are configured in addition to the peers discovered via Consul and take precedence
+    /// over them. The file is read on startup. Only JSON is supported, whatever the file extension.
+    #[arg(long)]
+    pub static_peers: Option<PathBuf>,
+
+    /// Act as a relay for peers that can't reach each other directly
+    ///
+    /// This is published for other peers to pick up. Relays need to have IP forwarding enabled.
+    #[arg(long)]
+    pub relay: bool,
+
+    /// How long to wait for a handshake with a peer before routing it via a relay
+    ///
+    /// This should be longer than the WireGuard rekey interval of 2 minutes.
+    #[arg(long, default_value = "5min", value_parser = humantime::parse_duration)]
+    pub relay_timeout: Duration,
+
     /// Public endpoint interface name
     ///
-    /// You need to provide either this or --endpoint-address.
+    /// You need to provide either this, --endpoint-address or --endpoint-stun.
     #[arg(long,
-        required_unless_present = "endpoint_address",
-        conflicts_with = "endpoint_address",
+        required_unless_present_any = ["endpoint_address", "endpoint_stun"],
+        conflicts_with_all = ["endpoint_address", "endpoint_stun"],
         value_parser = network_interface
     )]
     pub endpoint_interface: Option<NetworkInterface>,
 
+    /// IP family of the endpoint on --endpoint-interface
+    ///
+    /// If not provided, an endpoint in each family is published if available. Global addresses
+    /// are preferred over private and link-local ones. Temporary IPv6 addresses are never used.
+    #[arg(long, requires = "endpoint_interface")]
+    pub endpoint_family: Option<IpFamily>,
+
     /// Public endpoint address
     ///
     /// Can be a hostname or IP address.
-    /// You need to provide either this or --endpoint-interface.
+    /// You need to provide either this, --endpoint-interface or --endpoint-stun.
     #[arg(
         long,
-        required_unless_present = "endpoint_interface",
-        conflicts_with = "endpoint_interface"
+        required_unless_present_any = ["endpoint_interface", "endpoint_stun"],
+        conflicts_with_all = ["endpoint_interface", "endpoint_stun"]
     )]
     pub endpoint_address: Option<String>,
 
+    /// STUN server to discover the public endpoint with
+    ///
+    /// Useful for nodes behind NAT. For instance stun.example.com:3478. The port defaults to 3478.
+    /// The NAT mapping of the listen port is discovered on startup while later checks only
+    /// discover the public address and assume the mapped port stays the same.
+    /// You need to provide either this, --endpoint-interface or --endpoint-address.
+    #[arg(
+        long,
+        required_unless_present_any = ["endpoint_interface", "endpoint_address"],
+        conflicts_with_all = ["endpoint_interface", "endpoint_address"]
+    )]
+    pub endpoint_stun: Option<String>,
+
+    /// Endpoint used by peers in the same Consul datacenter
+    ///
+    /// Usually an address in a private network so that traffic within a datacenter doesn't leave
+    /// it. Peers in other datacenters keep using the public endpoint. Can be a hostname or IP
+    /// address with an optional port which defaults to --wg-port.
+    #[arg(long)]
+    pub endpoint_datacenter_address: Option<String>,
+
+    /// Additional endpoint under which this node might be reachable
+    ///
+    /// Provided as LABEL:PRIORITY=ENDPOINT, for instance private:10=10.1.0.5. The port defaults to
+    /// --wg-port. Peers try the candidates in the order of their priority, lowest first, and fall
+    /// back to the next one if they don't get a handshake. The endpoint detected via
+    /// --endpoint-interface, --endpoint-address or --endpoint-stun has a priority of 100. Can be
+    /// provided multiple times.
+    #[arg(long = "endpoint-candidate")]
+    pub endpoint_candidates: Vec<EndpointCandidate>,
+
+    /// How long to wait for a handshake with a peer before trying its next endpoint candidate
+    ///
+    /// This should be longer than the WireGuard rekey interval of 2 minutes.
+    #[arg(long, default_value = "5min", value_parser = humantime::parse_duration)]
+    pub endpoint_handshake_timeout: Duration,
+
+    /// How often to check whether the public endpoint changed
+    ///
+    /// Only used with --endpoint-interface and --endpoint-stun.
+    #[arg(long, default_value = "5min", value_parser = humantime::parse_duration)]
+    pub endpoint_refresh_period: Duration,
+
     /// Network configuration backend
     #[arg(long, default_value = "networkd")]
     pub network_backend: NetworkBackend,
@@ -80,20 +421,78 @@ pub struct CliArgs {
     #[arg(short, long)]
     pub address: Option<IpAddr>,
 
+    /// How to allocate addresses of new nodes if --address isn't provided
+    ///
+    /// Defaults to lowest-free in IPv4 networks and public-key-hash in IPv6 networks.
+    #[arg(long, global = true, value_enum)]
+    pub allocation_strategy: Option<AllocationStrategy>,
+
+    /// How long to wait before handing out the address of a node that is gone again
+    ///
+    /// Nodes record in Consul when they last used their address, so this works with every
+    /// --allocation-strategy.
+    #[arg(long, global = true, default_value = "0s", value_parser = humantime::parse_duration)]
+    pub address_quarantine: Duration,
+
+    /// Addresses that are never allocated to new nodes
+    ///
+    /// Either a network like 10.0.0.0/28 or a range like 10.0.0.1-10.0.0.10. Can be provided
+    /// multiple times. Further ranges are read from the "reservations" key under --consul-prefix.
+    /// Reserved addresses can still be assigned via --address.
+    #[arg(long = "reserve", global = true, value_name = "RANGE")]
+    pub reserved: Vec<AddressRange>,
+
+    /// Pin the address of this node to a stable identity so that it survives reinstalls
+    ///
+    /// The address is recorded in Consul under "leases/<identity>" and a new config for the same
+    /// identity reclaims it if no other node took it in the meantime.
+    #[arg(long, value_enum)]
+    pub lease_identity: Option<LeaseIdentity>,
+
     /// Network to use
     ///
     /// Must be the same for all clients.
     /// For instance 10.0.0.0/24 or fc00::/64
-    #[arg(short, long)]
-    pub network: IpNet,
+    #[arg(short, long, required = true)]
+    pub network: Option<IpNet>,
 
     /// Be verbose
     ///
     /// Provide twice for very verbose.
-    #[arg(short, long, action = clap::ArgAction::Count, value_parser = clap::value_parser!(u8).range(0..=2))]
+    #[arg(short, long, global = true, action = clap::ArgAction::Count, value_parser = clap::value_parser!(u8).range(0..=2))]
     pub verbose: u8,
 }
 
+impl CliArgs {
+    /// How to decide on the persistent keepalive for each peer
+    pub fn keepalive_policy(&self) -> KeepalivePolicy {
+        KeepalivePolicy {
+            default: self.keepalive,
+            own: self.advertise_keepalive,
+        }
+    }
+
+    /// WireGuard interface properties requested on the command line
+    pub fn link_options(&self) -> LinkOptions {
+        LinkOptions {
+            mtu: self.mtu,
+            fwmark: self.fwmark,
+            route_table: self.route_table.clone(),
+            route_metric: self.route_metric,
+        }
+    }
+
+    /// Tags to publish for this node
+    pub fn tags(&self) -> Tags {
+        self.tags.iter().cloned().collect()
+    }
+
+    /// Metadata to publish for this node
+    pub fn metadata(&self) -> NodeMetadata {
+        NodeMetadata::local(self.meta.iter().cloned().collect())
+    }
+}
+
 fn network_interface(s: &str) -> Result<NetworkInterface, String> {
     let interfaces = datalink::interfaces();
     let interface = interfaces
@@ -105,6 +504,11 @@ fn network_interface(s: &str) -> Result<NetworkInterface, String> {
     }
 }
 
+fn key_value(s: &str) -> Result<(String, String), String> {
+    let (key, value) = s.split_once('=').ok_or("Expected KEY=VALUE")?;
+    Ok((key.to_string(), value.to_string()))
+}
+
 fn keep_alive(s: &str) -> Result<u64, humantime::DurationError> {
     let duration = humantime::parse_duration(s)?;
     Ok(duration.as_secs())
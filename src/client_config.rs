@@ -0,0 +1,125 @@
+use std::collections::HashSet;
+
+use anyhow::{ensure, Context, Result};
+use ipnet::IpNet;
+use qrcode::{render::unicode::Dense1x2, QrCode};
+use wireguard_keys::{Privkey, Pubkey};
+
+use crate::wireguard::WgPeer;
+
+/// # Config of a roaming device using the stock WireGuard apps
+///
+/// The device is registered in Consul as a peer without an endpoint so that the nodes of the mesh
+/// wait for it to connect to them.
+pub struct ClientConfig {
+    pub name: String,
+    pub private_key: Privkey,
+
+    /// Address of the device, with the prefix length of the mesh network.
+    pub address: IpNet,
+
+    /// The peers of the device along with the IPs routed to each of them.
+    pub peers: Vec<(WgPeer, Vec<IpNet>)>,
+
+    /// Persistent keepalive in seconds the device uses to stay reachable behind NAT.
+    pub keepalive: u64,
+
+    /// The only node the device peers with, if any.
+    pub hub: Option<Pubkey>,
+}
+
+impl ClientConfig {
+    /// Build the config of a new device
+    ///
+    /// With a hub, the device only peers with it and routes the whole network via it. Otherwise,
+    /// it peers with all peers that have an endpoint.
+    pub fn new(
+        name: &str,
+        private_key: Privkey,
+        address: IpNet,
+        peers: &HashSet<WgPeer>,
+        hub: Option<Pubkey>,
+        keepalive: u64,
+    ) -> Result<Self> {
+        let mut peers = match hub {
+            Some(hub) => {
+                let hub = peers
+                    .iter()
+                    .find(|x| x.public_key == hub)
+                    .context(format!("Hub {} isn't a peer", hub.to_base64_urlsafe()))?;
+                ensure!(
+                    !hub.endpoint.is_empty(),
+                    "Hub {} has no endpoint the device could connect to",
+                    hub.public_key.to_base64_urlsafe()
+                );
+                vec![(hub.clone(), vec![address.trunc()])]
+            }
+            None => peers
+                .iter()
+                .filter(|x| !x.endpoint.is_empty())
+                .map(|x| (x.clone(), vec![x.address]))
+                .collect(),
+        };
+        peers.sort_by_key(|(peer, _)| peer.address);
+
+        Ok(Self {
+            name: name.to_string(),
+            private_key,
+            address,
+            peers,
+            keepalive,
+            hub,
+        })
+    }
+
+    /// The peer config of the device to publish in Consul
+    ///
+    /// With a hub, the other nodes route the address of the device via the hub.
+    pub fn wg_peer(&self) -> WgPeer {
+        let mut wgpeer = WgPeer::new(self.private_key.pubkey(), "", self.address.addr());
+        wgpeer.metadata.hostname = Some(self.name.clone());
+        wgpeer.hub = self.hub;
+        wgpeer
+    }
+
+    /// Render the config in the format of wg-quick(8)
+    pub fn to_wg_quick(&self) -> String {
+        let mut config = format!(
+            "# {}\n[Interface]\nPrivateKey = {}\nAddress = {}\n",
+            self.name,
+            self.private_key.to_base64(),
+            self.address
+        );
+        for (peer, allowed_ips) in &self.peers {
+            config.push('\n');
+            if let Some(description) = peer.metadata.description() {
+                config.push_str(&format!("# {description}\n"));
+            }
+            let allowed_ips = allowed_ips
+                .iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            config.push_str(&format!(
+                "[Peer]\nPublicKey = {}\nEndpoint = {}\nAllowedIPs = {allowed_ips}\n",
+                peer.public_key.to_base64(),
+                peer.endpoint
+            ));
+            if self.keepalive != 0 {
+                config.push_str(&format!("PersistentKeepalive = {}\n", self.keepalive));
+            }
+        }
+        config
+    }
+
+    /// Render the config as a QR code for the terminal which the mobile apps can scan
+    pub fn to_qr_code(&self) -> Result<String> {
+        let code = QrCode::new(self.to_wg_quick()).context("Config is too large for a QR code")?;
+        // Terminals usually have a dark background, so invert the colors.
+        Ok(code
+            .render::<Dense1x2>()
+            .dark_color(Dense1x2::Light)
+            .light_color(Dense1x2::Dark)
+            .build())
+    }
+}
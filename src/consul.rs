@@ -1,5 +1,7 @@
 use std::{collections::HashSet, future::Future, time::Duration};
 
+use serde::de::DeserializeOwned;
+
 use anyhow::{anyhow, bail, Context, Result};
 use base64::prelude::{Engine as _, BASE64_STANDARD};
 use futures::future::join_all;
@@ -17,7 +19,15 @@ use tracing::{error, info, trace, warn};
 use uuid::Uuid;
 use wireguard_keys::Pubkey;
 
-use crate::{wireguard::WgPeer, CONSUL_TTL};
+use crate::{
+    allocation::{AddressRange, AddressUsage},
+    enrolment::{self, Approval, Enrolments, JoinClaim, JoinToken},
+    lease::AddressLease,
+    policy::PeeringPolicy,
+    signing::{AuthorityKey, Enrolment, MeshAuthority},
+    wireguard::WgPeer,
+    CONSUL_TTL,
+};
 
 /// Allows for gracefully telling a background task to shut down and to then join it.
 #[must_use]
@@ -64,6 +74,8 @@ pub struct ConsulClient {
     pub http_client: reqwest::Client,
     api_base_url: Url,
     pub kv_api_base_url: Url,
+    authority: Option<MeshAuthority>,
+    authority_key: Option<AuthorityKey>,
 }
 
 #[derive(Debug, Eq, PartialEq, Hash, Deserialize)]
@@ -75,6 +87,104 @@ pub struct ConsulKvGet {
     pub lock_index: u64,
     pub modify_index: u64,
     pub value: String,
+    #[serde(default)]
+    pub session: Option<Uuid>,
+}
+
+/// Where a peer config is published
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PeerState {
+    /// The peer takes part in the mesh, its config is stored under `peers/`.
+    Active,
+
+    /// The peer waits for an operator to approve it, its config is stored under `pending/`.
+    Pending,
+}
+
+impl PeerState {
+    fn directory(self) -> &'static str {
+        match self {
+            PeerState::Active => "peers/",
+            PeerState::Pending => "pending/",
+        }
+    }
+}
+
+/// Version of the peer record schema written by this version of wiresmith
+pub const PEER_RECORD_VERSION: u32 = 1;
+
+/// # Peer config as stored in Consul
+///
+/// This is the JSON of [`WgPeer`] with a `version` field next to its fields. Records written by
+/// wiresmith versions before the field was introduced are treated as version 0 which has the same
+/// layout as version 1.
+///
+/// To allow for rolling out new versions gradually, the following rules apply:
+///
+/// - New fields need to be optional and readers ignore fields they don't know, so adding fields
+///   doesn't change the version.
+/// - The version is only increased for incompatible changes such as removing or changing the
+///   meaning of a field. Readers skip records with a version newer than they understand.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PeerRecord {
+    #[serde(default)]
+    pub version: u32,
+
+    /// Enrolment of the peer signed by the mesh authority.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enrolment: Option<Enrolment>,
+
+    #[serde(flatten)]
+    pub peer: WgPeer,
+}
+
+impl PeerRecord {
+    pub fn new(peer: WgPeer, enrolment: Option<Enrolment>) -> Self {
+        Self {
+            version: PEER_RECORD_VERSION,
+            enrolment,
+            peer,
+        }
+    }
+
+    /// Parse a record, failing on records with a newer version than we understand
+    ///
+    /// If a mesh authority is given, records without an enrolment signed by it for the public key
+    /// and address of the peer are rejected as well.
+    pub fn parse(value: &[u8], authority: Option<&MeshAuthority>) -> Result<WgPeer> {
+        let value: serde_json::Value = serde_json::from_slice(value).context("Invalid JSON")?;
+
+        let version = value.get("version").map_or(Some(0), |x| x.as_u64());
+        match version {
+            Some(version) if version <= PEER_RECORD_VERSION.into() => {}
+            Some(version) => bail!(
+                "Record has version {version} but we only understand up to {PEER_RECORD_VERSION}"
+            ),
+            None => bail!("Record has an invalid version"),
+        }
+        let record: PeerRecord = serde_json::from_value(value).context("Invalid peer record")?;
+        if let Some(authority) = authority {
+            let enrolment = record.enrolment.context("Record has no enrolment")?;
+            authority.verify_enrolment(
+                &enrolment,
+                record.peer.public_key,
+                record.peer.address.addr(),
+            )?;
+        }
+        Ok(record.peer)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct AgentSelf {
+    config: AgentConfig,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct AgentConfig {
+    datacenter: String,
 }
 
 #[derive(Serialize)]
@@ -165,26 +275,70 @@ impl ConsulClient {
             http_client: client,
             api_base_url: consul_address,
             kv_api_base_url,
+            authority: None,
+            authority_key: None,
         })
     }
 
-    /// # Read all peer configs
+    /// # Require peers to be enrolled by the mesh authority
     ///
-    /// This reads the WireGuard peer configs from all available Consul DCs and merges the sets
-    /// together.
+    /// Peers without an enrolment signed by the authority for their public key and address are
+    /// rejected.
+    pub fn with_authority(self, authority: MeshAuthority) -> Self {
+        Self {
+            authority: Some(authority),
+            ..self
+        }
+    }
+
+    /// # Sign operator decisions with the secret key of the mesh authority
+    ///
+    /// Nodes with a mesh authority ignore approvals that aren't signed by it.
+    pub fn with_authority_key(self, authority_key: AuthorityKey) -> Self {
+        Self {
+            authority_key: Some(authority_key),
+            ..self
+        }
+    }
+
+    /// # Get the datacenter of the Consul agent we're talking to
+    ///
+    /// This is also the datacenter our own config ends up in.
     #[tracing::instrument(skip(self))]
-    pub async fn get_peers(&self) -> Result<HashSet<WgPeer>> {
-        let dcs = self
+    pub async fn local_datacenter(&self) -> Result<String> {
+        let agent = self
             .http_client
-            .get(self.api_base_url.join("v1/catalog/datacenters")?)
+            .get(self.api_base_url.join("v1/agent/self")?)
             .send()
             .await?
             .error_for_status()?
-            .json::<Vec<String>>()
-            .await?;
+            .json::<AgentSelf>()
+            .await
+            .context("Failed to parse Consul agent info")?;
+        Ok(agent.config.datacenter)
+    }
+
+    /// # Read all peer configs
+    ///
+    /// This reads the WireGuard peer configs from all available Consul DCs and merges the sets
+    /// together. Each peer remembers the DC it was read from. Peers waiting for approval aren't
+    /// included, see [`Self::get_pending_peers`].
+    #[tracing::instrument(skip(self))]
+    pub async fn get_peers(&self) -> Result<HashSet<WgPeer>> {
+        self.get_peers_in(PeerState::Active).await
+    }
+
+    /// # Read the configs of all peers waiting for approval
+    #[tracing::instrument(skip(self))]
+    pub async fn get_pending_peers(&self) -> Result<HashSet<WgPeer>> {
+        self.get_peers_in(PeerState::Pending).await
+    }
+
+    async fn get_peers_in(&self, state: PeerState) -> Result<HashSet<WgPeer>> {
+        let dcs = self.datacenters().await?;
 
         let mut peers = HashSet::new();
-        for dc_peers in join_all(dcs.iter().map(|dc| self.get_peers_for_dc(dc))).await {
+        for dc_peers in join_all(dcs.iter().map(|dc| self.get_peers_for_dc(dc, state))).await {
             let dc_peers = dc_peers?;
             peers.extend(dc_peers);
         }
@@ -192,17 +346,29 @@ impl ConsulClient {
         Ok(peers)
     }
 
+    /// # List all Consul DCs
+    async fn datacenters(&self) -> Result<Vec<String>> {
+        Ok(self
+            .http_client
+            .get(self.api_base_url.join("v1/catalog/datacenters")?)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Vec<String>>()
+            .await?)
+    }
+
     /// # Read peers for a single DC
     ///
-    /// This will read the all of the WireGuard peers from a given Consul DC. This should only be
-    /// called by [`Self::get_peers`].
+    /// This will read the all of the WireGuard peers in the given state from a given Consul DC.
+    /// This should only be called by [`Self::get_peers`] and [`Self::get_pending_peers`].
     #[tracing::instrument(skip(self))]
-    async fn get_peers_for_dc(&self, dc: &str) -> Result<HashSet<WgPeer>> {
+    async fn get_peers_for_dc(&self, dc: &str, state: PeerState) -> Result<HashSet<WgPeer>> {
         // When the Consul server which is the Raft leader is restarted all KV reads by default
         // return 500 errors until a new Raft leader is elected. For our usecase it's fine if the
         // read value is a bit stale though, so prevent spurious errors by always performing stale
         // reads.
-        let mut peers_url = self.kv_api_base_url.join("peers/")?;
+        let mut peers_url = self.kv_api_base_url.join(state.directory())?;
         peers_url
             .query_pairs_mut()
             .append_pair("recurse", "true")
@@ -220,12 +386,22 @@ impl ConsulClient {
                 let kv_get: HashSet<ConsulKvGet> = resp.json().await?;
                 let wgpeers: HashSet<_> = kv_get
                     .into_iter()
-                    .map(|x| {
-                        let decoded = &BASE64_STANDARD
-                            .decode(x.value)
-                            .expect("Can't decode base64");
-                        serde_json::from_slice(decoded)
-                            .expect("Can't interpret JSON out of decoded base64")
+                    .filter_map(|x| {
+                        // A single broken record mustn't take down the whole mesh, so we skip it.
+                        let wgpeer = BASE64_STANDARD
+                            .decode(&x.value)
+                            .context("Can't decode base64")
+                            .and_then(|decoded| {
+                                PeerRecord::parse(&decoded, self.authority.as_ref())
+                            })
+                            .inspect_err(|err| {
+                                warn!("Skipping invalid peer record {} in {dc}: {err:#}", x.key)
+                            })
+                            .ok()?;
+                        Some(WgPeer {
+                            datacenter: Some(dc.to_string()),
+                            ..wgpeer
+                        })
                     })
                     .collect();
                 Ok(wgpeers)
@@ -239,17 +415,401 @@ impl ConsulClient {
         }
     }
 
+    /// # Read the peering policy
+    ///
+    /// The policy is stored as JSON in the `policy` key under the prefix. Returns `None` if there
+    /// is no policy.
+    #[tracing::instrument(skip(self))]
+    pub async fn get_policy(&self) -> Result<Option<PeeringPolicy>> {
+        let mut policy_url = self.kv_api_base_url.join("policy")?;
+        policy_url
+            .query_pairs_mut()
+            .append_pair("raw", "true")
+            .append_pair("stale", "1");
+
+        let resp = self.http_client.get(policy_url).send().await?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let policy = resp
+            .error_for_status()?
+            .json()
+            .await
+            .context("Failed to parse peering policy from Consul")?;
+        Ok(Some(policy))
+    }
+
+    /// # Read the reserved address ranges
+    ///
+    /// The reservations are stored as a JSON list of ranges in the `reservations` key under the
+    /// prefix, for instance `["10.0.0.1-10.0.0.10", "10.0.1.0/24"]`.
+    #[tracing::instrument(skip(self))]
+    pub async fn get_reservations(&self) -> Result<Vec<AddressRange>> {
+        let mut reservations_url = self.kv_api_base_url.join("reservations")?;
+        reservations_url
+            .query_pairs_mut()
+            .append_pair("raw", "true")
+            .append_pair("stale", "1");
+
+        let resp = self.http_client.get(reservations_url).send().await?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(vec![]);
+        }
+        resp.error_for_status()?
+            .json()
+            .await
+            .context("Failed to parse address reservations from Consul")
+    }
+
+    /// # Create a join token
+    ///
+    /// The secret of the token needs to be passed to the new node via `--join-token`. Only its
+    /// hash is stored in Consul.
+    #[tracing::instrument(skip(self, secret))]
+    pub async fn create_join_token(&self, secret: &str, token: &JoinToken) -> Result<()> {
+        let mut token_url = self.join_token_url(secret)?;
+        token_url.query_pairs_mut().append_pair("cas", "0");
+
+        let created = self
+            .http_client
+            .put(token_url)
+            .json(token)
+            .send()
+            .await?
+            .error_for_status()
+            .context("Failed to put join token into Consul")?
+            .json::<bool>()
+            .await
+            .context("Failed to parse Consul KV put response")?;
+        if !created {
+            bail!("A join token with the same ID already exists");
+        }
+        Ok(())
+    }
+
+    /// # Claim a join token for the given node
+    ///
+    /// Fails if the token doesn't exist, has expired or was claimed by another node. Claiming a
+    /// token we already claimed ourselves succeeds so that nodes can be restarted with the same
+    /// arguments.
+    #[tracing::instrument(skip(self, secret))]
+    pub async fn claim_join_token(&self, secret: &str, public_key: Pubkey) -> Result<JoinToken> {
+        let token = self.get_join_token(secret).await?;
+        let claim_url = self
+            .kv_api_base_url
+            .join("claims/")?
+            .join(&enrolment::token_id(secret))?;
+
+        let mut get_url = claim_url.clone();
+        get_url.query_pairs_mut().append_pair("raw", "true");
+        let resp = self.http_client.get(get_url).send().await?;
+        if resp.status() != StatusCode::NOT_FOUND {
+            let claim = resp
+                .error_for_status()?
+                .json::<JoinClaim>()
+                .await
+                .context("Failed to parse join token claim from Consul")?;
+            if claim.public_key == public_key {
+                return Ok(token);
+            }
+            bail!(
+                "Join token was already claimed by {}",
+                claim.public_key.to_base64_urlsafe()
+            );
+        }
+        if token.is_expired()? {
+            bail!("Join token expired at {}", token.expires_at);
+        }
+
+        // Only write the claim if nobody else claimed the token in the meantime.
+        let mut put_url = claim_url;
+        put_url.query_pairs_mut().append_pair("cas", "0");
+        let claimed = self
+            .http_client
+            .put(put_url)
+            .json(&JoinClaim::new(secret, public_key)?)
+            .send()
+            .await?
+            .error_for_status()
+            .context("Failed to claim join token")?
+            .json::<bool>()
+            .await
+            .context("Failed to parse Consul KV put response")?;
+        if !claimed {
+            bail!("Join token was claimed concurrently by another node");
+        }
+        Ok(token)
+    }
+
+    /// # Read a join token by its secret
+    #[tracing::instrument(skip(self, secret))]
+    pub async fn get_join_token(&self, secret: &str) -> Result<JoinToken> {
+        let mut token_url = self.join_token_url(secret)?;
+        token_url.query_pairs_mut().append_pair("raw", "true");
+
+        let resp = self.http_client.get(token_url).send().await?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            bail!("Unknown join token");
+        }
+        resp.error_for_status()?
+            .json()
+            .await
+            .context("Invalid join token")
+    }
+
+    /// # Record the decision of an operator about a node
+    ///
+    /// The decision is signed if we were given the secret key of the mesh authority.
+    #[tracing::instrument(skip(self))]
+    pub async fn decide_approval(&self, public_key: Pubkey, approved: bool) -> Result<()> {
+        let approval_url = self
+            .kv_api_base_url
+            .join("approvals/")?
+            .join(&public_key.to_base64_urlsafe())?;
+        let mut approval = Approval::new(public_key, approved);
+        if let Some(authority_key) = &self.authority_key {
+            approval = approval.sign(authority_key)?;
+        }
+        self.http_client
+            .put(approval_url)
+            .json(&approval)
+            .send()
+            .await?
+            .error_for_status()
+            .context("Failed to put approval into Consul")?;
+        Ok(())
+    }
+
+    /// # Get the claimed join tokens and approval decisions of all nodes
+    ///
+    /// These are read from all Consul DCs. Claims are verified, see [`Enrolments::verify`].
+    #[tracing::instrument(skip(self))]
+    pub async fn get_enrolments(&self) -> Result<Enrolments> {
+        let tokens = self.get_all::<JoinToken>("join-tokens/").await?;
+        let claims = self.get_all::<JoinClaim>("claims/").await?;
+        let approvals = self.get_all::<Approval>("approvals/").await?;
+        Ok(Enrolments::verify(
+            &tokens,
+            &claims,
+            &approvals,
+            self.authority.as_ref(),
+        ))
+    }
+
+    /// # Record that a node is using its address
+    #[tracing::instrument(skip(self))]
+    pub async fn update_address_usage(&self, usage: &AddressUsage) -> Result<()> {
+        let usage_url = self
+            .kv_api_base_url
+            .join("addresses/")?
+            .join(&usage.public_key.to_base64_urlsafe())?;
+        self.http_client
+            .put(usage_url)
+            .json(usage)
+            .send()
+            .await?
+            .error_for_status()
+            .context("Failed to put address usage into Consul")?;
+        Ok(())
+    }
+
+    /// # Get when the addresses of all nodes were last used
+    ///
+    /// These are read from all Consul DCs.
+    #[tracing::instrument(skip(self))]
+    pub async fn get_address_usage(&self) -> Result<Vec<AddressUsage>> {
+        self.get_all("addresses/").await
+    }
+
+    /// # Record the address lease of a node
+    #[tracing::instrument(skip(self))]
+    pub async fn put_lease(&self, lease: &AddressLease) -> Result<()> {
+        let lease_url = self
+            .kv_api_base_url
+            .join("leases/")?
+            .join(&lease.identity)?;
+        self.http_client
+            .put(lease_url)
+            .json(lease)
+            .send()
+            .await?
+            .error_for_status()
+            .context("Failed to put address lease into Consul")?;
+        Ok(())
+    }
+
+    /// # Get the address lease of an identity
+    ///
+    /// Returns `None` if there is no lease.
+    #[tracing::instrument(skip(self))]
+    pub async fn get_lease(&self, identity: &str) -> Result<Option<AddressLease>> {
+        let mut lease_url = self.kv_api_base_url.join("leases/")?.join(identity)?;
+        lease_url.query_pairs_mut().append_pair("raw", "true");
+
+        let resp = self.http_client.get(lease_url).send().await?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let lease = resp
+            .error_for_status()?
+            .json()
+            .await
+            .context("Failed to parse address lease from Consul")?;
+        Ok(Some(lease))
+    }
+
+    /// # Read all JSON values under a directory of the prefix in all Consul DCs
+    ///
+    /// Invalid values are skipped.
+    async fn get_all<T: DeserializeOwned>(&self, directory: &str) -> Result<Vec<T>> {
+        let mut values = vec![];
+        for dc in self.datacenters().await? {
+            let mut url = self.kv_api_base_url.join(directory)?;
+            url.query_pairs_mut()
+                .append_pair("recurse", "true")
+                .append_pair("dc", &dc)
+                .append_pair("stale", "1");
+
+            let resp = self.http_client.get(url).send().await?;
+            if resp.status() == StatusCode::NOT_FOUND {
+                continue;
+            }
+            let kv_get = resp
+                .error_for_status()?
+                .json::<Vec<ConsulKvGet>>()
+                .await
+                .context("Failed to parse KV response")?;
+            values.extend(kv_get.into_iter().filter_map(|x| {
+                BASE64_STANDARD
+                    .decode(&x.value)
+                    .context("Can't decode base64")
+                    .and_then(|decoded| Ok(serde_json::from_slice(&decoded)?))
+                    .inspect_err(|err| warn!("Skipping invalid value {} in {dc}: {err:#}", x.key))
+                    .ok()
+            }));
+        }
+        Ok(values)
+    }
+
+    /// # Register a peer that doesn't run wiresmith
+    ///
+    /// Unlike the configs of nodes running wiresmith, the config isn't tied to a session and
+    /// stays until it's removed via [`Self::remove_external_peer`]. Fails if there already is a
+    /// peer with the same public key.
+    ///
+    /// Registering a peer is an operator decision, so the peer is approved right away. This way
+    /// nodes started with `--require-enrolment` or `--require-approval` pick it up as well.
+    #[tracing::instrument(skip(self))]
+    pub async fn add_external_peer(
+        &self,
+        wgpeer: &WgPeer,
+        enrolment: Option<Enrolment>,
+    ) -> Result<()> {
+        let mut put_url = self.peer_url(PeerState::Active, wgpeer.public_key)?;
+        put_url.query_pairs_mut().append_pair("cas", "0");
+
+        let created = self
+            .http_client
+            .put(put_url)
+            .json(&PeerRecord::new(wgpeer.clone(), enrolment))
+            .send()
+            .await?
+            .error_for_status()
+            .context("Failed to put peer config into Consul")?
+            .json::<bool>()
+            .await
+            .context("Failed to parse Consul KV put response")?;
+        if !created {
+            bail!(
+                "There already is a peer with public key {}",
+                wgpeer.public_key.to_base64_urlsafe()
+            );
+        }
+        self.decide_approval(wgpeer.public_key, true).await?;
+        self.update_address_usage(&AddressUsage::new(wgpeer.public_key, wgpeer.address.addr()))
+            .await
+    }
+
+    /// # Remove a peer registered via [`Self::add_external_peer`]
+    ///
+    /// Configs of nodes running wiresmith are refused as they'd be published again right away.
+    #[tracing::instrument(skip(self))]
+    pub async fn remove_external_peer(&self, public_key: Pubkey) -> Result<()> {
+        let peer_url = self.peer_url(PeerState::Active, public_key)?;
+        let resp = self.http_client.get(peer_url.clone()).send().await?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            bail!(
+                "There is no peer with public key {}",
+                public_key.to_base64_urlsafe()
+            );
+        }
+        let keys = resp
+            .error_for_status()?
+            .json::<Vec<ConsulKvGet>>()
+            .await
+            .context("Failed to parse KV response")?;
+        let key = keys
+            .into_iter()
+            .next()
+            .context("Consul unexpectedly returned an empty array")?;
+        if key.session.is_some() {
+            bail!("Peer is managed by a running wiresmith node");
+        }
+        let address = BASE64_STANDARD
+            .decode(&key.value)
+            .ok()
+            .and_then(|x| PeerRecord::parse(&x, None).ok())
+            .map(|x| x.address.addr());
+
+        self.http_client
+            .delete(peer_url)
+            .send()
+            .await?
+            .error_for_status()
+            .context("Failed to delete peer config from Consul")?;
+
+        // The quarantine of the address starts now.
+        match address {
+            Some(address) => {
+                self.update_address_usage(&AddressUsage::new(public_key, address))
+                    .await
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// URL of the key holding the config of the peer with the given public key
+    fn peer_url(&self, state: PeerState, public_key: Pubkey) -> Result<Url> {
+        Ok(self
+            .kv_api_base_url
+            .join(state.directory())?
+            .join(&public_key.to_base64_urlsafe())?)
+    }
+
+    /// URL of the key holding the join token with the given secret
+    fn join_token_url(&self, secret: &str) -> Result<Url> {
+        Ok(self
+            .kv_api_base_url
+            .join("join-tokens/")?
+            .join(&enrolment::token_id(secret))?)
+    }
+
     /// # Create a Consul session
     ///
     /// This starts a background task which renews the session based on the given session TTL. If
     /// renewing the session fails, the passed in cancellation token is cancelled. On cancellation
     /// the keys that locks are held for are deleted.
     ///
+    /// Configs put via the session are published as peers in the given state along with the given
+    /// enrolment.
+    ///
     /// See [`ConsulSession`] for more information.
     #[tracing::instrument(skip(self, parent_token))]
     pub async fn create_session(
         &self,
         public_key: Pubkey,
+        state: PeerState,
+        enrolment: Option<Enrolment>,
         parent_token: CancellationToken,
     ) -> Result<ConsulSession> {
         let url = self.api_base_url.join("v1/session/create")?;
@@ -279,6 +839,8 @@ impl ConsulClient {
         Ok(ConsulSession {
             client: self.clone(),
             id: res.id,
+            state,
+            enrolment,
             cancellator: TaskCancellator::new(join_handle, session_token),
         })
     }
@@ -380,6 +942,10 @@ fn session_handler(
 pub struct ConsulSession {
     client: ConsulClient,
     id: Uuid,
+    /// Whether our config is published as an active or as a pending peer
+    state: PeerState,
+    /// Our enrolment which is published along with our config
+    enrolment: Option<Enrolment>,
     cancellator: TaskCancellator,
 }
 
@@ -407,13 +973,31 @@ impl ConsulSession {
         wgpeer: &WgPeer,
         parent_token: CancellationToken,
     ) -> Result<TaskCancellator> {
-        let peer_url = self
-            .client
-            .kv_api_base_url
-            .join("peers/")?
-            .join(&wgpeer.public_key.to_base64_urlsafe())?;
+        self.update_config(wgpeer).await?;
+
+        info!("Wrote node config into Consul");
+
+        let client = self.client.clone();
+        let config_token = CancellationToken::new();
+        let join_handle = tokio::spawn(config_handler(
+            client,
+            self.id,
+            self.peer_url(wgpeer)?,
+            config_token.clone(),
+            parent_token,
+        ));
 
-        let mut put_url = peer_url.clone();
+        Ok(TaskCancellator::new(join_handle, config_token))
+    }
+
+    /// # Update own WireGuard peer config
+    ///
+    /// This writes the given config while holding on to the lock of this session. It's used by
+    /// [`Self::put_config`] and can be called again afterwards to update the published config
+    /// without giving up the lock.
+    #[tracing::instrument(skip(self, wgpeer))]
+    pub async fn update_config(&self, wgpeer: &WgPeer) -> Result<()> {
+        let mut put_url = self.peer_url(wgpeer)?;
         put_url
             .query_pairs_mut()
             .append_pair("acquire", &self.id.to_string());
@@ -424,7 +1008,7 @@ impl ConsulSession {
             .client
             .http_client
             .put(put_url)
-            .json(wgpeer)
+            .json(&PeerRecord::new(wgpeer.clone(), self.enrolment.clone()))
             .send()
             .await?
             .error_for_status()
@@ -436,19 +1020,12 @@ impl ConsulSession {
             bail!("Did not get Consul lock for node config");
         }
 
-        info!("Wrote node config into Consul");
-
-        let client = self.client.clone();
-        let config_token = CancellationToken::new();
-        let join_handle = tokio::spawn(config_handler(
-            client,
-            self.id,
-            peer_url,
-            config_token.clone(),
-            parent_token,
-        ));
+        Ok(())
+    }
 
-        Ok(TaskCancellator::new(join_handle, config_token))
+    /// URL of the key holding the config of the given peer
+    fn peer_url(&self, wgpeer: &WgPeer) -> Result<Url> {
+        self.client.peer_url(self.state, wgpeer.public_key)
     }
 }
 
@@ -0,0 +1,330 @@
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{ensure, Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use wireguard_keys::Pubkey;
+
+use crate::wireguard::{KeepalivePolicy, WgPeer};
+
+/// `IFA_F_TEMPORARY` from `linux/if_addr.h`, set for IPv6 privacy addresses
+const IFA_F_TEMPORARY: u32 = 0x01;
+
+/// `IFA_F_DEPRECATED` from `linux/if_addr.h`, set for addresses past their preferred lifetime
+const IFA_F_DEPRECATED: u32 = 0x20;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, ValueEnum)]
+pub enum IpFamily {
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    pub fn of(ip: &IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(_) => Self::V4,
+            IpAddr::V6(_) => Self::V6,
+        }
+    }
+}
+
+/// Scope of an address, ordered by how much we prefer it as an endpoint
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+enum AddressScope {
+    LinkLocal,
+    Private,
+    Global,
+}
+
+impl AddressScope {
+    /// Returns `None` for addresses that can't be used as an endpoint at all.
+    fn of(ip: &IpAddr) -> Option<Self> {
+        match ip {
+            IpAddr::V4(ip) => {
+                if ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() {
+                    None
+                } else if ip.is_link_local() {
+                    Some(Self::LinkLocal)
+                } else if ip.is_private() {
+                    Some(Self::Private)
+                } else {
+                    Some(Self::Global)
+                }
+            }
+            IpAddr::V6(ip) => {
+                if ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() {
+                    None
+                } else if ip.is_unicast_link_local() {
+                    Some(Self::LinkLocal)
+                } else if ip.is_unique_local() {
+                    Some(Self::Private)
+                } else {
+                    Some(Self::Global)
+                }
+            }
+        }
+    }
+}
+
+/// Read the IPv6 addresses of an interface that shouldn't be used as an endpoint
+///
+/// These are temporary privacy addresses (RFC 8981) which are rotated regularly as well as
+/// deprecated addresses which are about to go away.
+pub fn unstable_ipv6_addresses(interface: &str) -> Result<HashSet<Ipv6Addr>> {
+    let if_inet6 = match std::fs::read_to_string("/proc/net/if_inet6") {
+        Ok(if_inet6) => if_inet6,
+        // The file doesn't exist if IPv6 is disabled, so there are no IPv6 addresses at all.
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(err) => return Err(err).context("Couldn't read /proc/net/if_inet6"),
+    };
+
+    let mut addresses = HashSet::new();
+    for line in if_inet6.lines() {
+        // Each line looks like this:
+        // 20010db8000000000000000000000001 02 40 00 01     eth0
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let [address, _, _, _, flags, name] = fields[..] else {
+            continue;
+        };
+        if name != interface {
+            continue;
+        }
+        let flags = u32::from_str_radix(flags, 16)?;
+        if flags & (IFA_F_TEMPORARY | IFA_F_DEPRECATED) != 0 {
+            addresses.insert(Ipv6Addr::from(u128::from_str_radix(address, 16)?));
+        }
+    }
+    Ok(addresses)
+}
+
+/// Select the addresses to use as endpoints out of all addresses of the endpoint interface
+///
+/// At most one address per family is returned. Global addresses are preferred over private
+/// addresses which are preferred over link-local addresses. The first returned address is the
+/// most preferred one, IPv4 wins if both families have the same scope.
+pub fn select_endpoint_ips(
+    ips: &[IpAddr],
+    family: Option<IpFamily>,
+    excluded: &HashSet<Ipv6Addr>,
+) -> Vec<IpAddr> {
+    let best_of_family = |wanted: IpFamily| {
+        ips.iter()
+            .filter(|ip| IpFamily::of(ip) == wanted)
+            .filter(|ip| !matches!(ip, IpAddr::V6(ip) if excluded.contains(ip)))
+            .filter_map(|ip| Some((AddressScope::of(ip)?, *ip)))
+            .min_by_key(|(scope, _)| Reverse(*scope))
+    };
+
+    let mut candidates = [IpFamily::V4, IpFamily::V6]
+        .into_iter()
+        .filter(|x| family.is_none_or(|family| family == *x))
+        .filter_map(best_of_family)
+        .collect::<Vec<_>>();
+    // This is a stable sort so IPv4 stays first for equal scopes.
+    candidates.sort_by_key(|(scope, _)| Reverse(*scope));
+    candidates.into_iter().map(|(_, ip)| ip).collect()
+}
+
+/// Format an endpoint the way WireGuard expects it
+///
+/// IPv6 addresses need to be put in brackets. Hostnames are passed through as they are.
+pub fn format_endpoint(host: &str, port: u16) -> String {
+    match host.parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, port).to_string(),
+        Err(_) => format!("{host}:{port}"),
+    }
+}
+
+/// Get the IP family of an endpoint if it's an IP address rather than a hostname
+pub fn endpoint_family(endpoint: &str) -> Option<IpFamily> {
+    endpoint
+        .parse::<SocketAddr>()
+        .ok()
+        .map(|x| IpFamily::of(&x.ip()))
+}
+
+/// Priority of endpoints for which none was provided explicitly
+pub const DEFAULT_ENDPOINT_PRIORITY: u32 = 100;
+
+/// An endpoint under which a peer might be reachable
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct EndpointCandidate {
+    pub endpoint: String,
+
+    /// Free-form label describing the network this endpoint is in, e.g. "private" or "public".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+
+    /// Lower values are preferred.
+    pub priority: u32,
+}
+
+impl FromStr for EndpointCandidate {
+    type Err = anyhow::Error;
+
+    /// Parse a candidate in the form of `LABEL:PRIORITY=ENDPOINT`
+    ///
+    /// The endpoint may omit the port, in which case it needs to be filled in later on.
+    fn from_str(s: &str) -> Result<Self> {
+        let (label_and_priority, endpoint) = s
+            .split_once('=')
+            .context("Expected LABEL:PRIORITY=ENDPOINT")?;
+        let (label, priority) = label_and_priority
+            .split_once(':')
+            .context("Expected LABEL:PRIORITY=ENDPOINT")?;
+        ensure!(!endpoint.is_empty(), "Endpoint must not be empty");
+        Ok(Self {
+            endpoint: endpoint.to_string(),
+            label: (!label.is_empty()).then(|| label.to_string()),
+            priority: priority.parse().context("Invalid priority")?,
+        })
+    }
+}
+
+/// Add a port to an endpoint if it doesn't have one yet
+///
+/// IPv6 addresses may be given with or without brackets.
+pub fn with_default_port(endpoint: &str, port: u16) -> String {
+    let host = endpoint
+        .strip_prefix('[')
+        .and_then(|x| x.strip_suffix(']'))
+        .unwrap_or(endpoint);
+    if endpoint.parse::<SocketAddr>().is_ok()
+        || (host.parse::<IpAddr>().is_err() && endpoint.contains(':'))
+    {
+        endpoint.to_string()
+    } else {
+        format_endpoint(host, port)
+    }
+}
+
+/// Which candidate we're currently using for a peer
+struct Selection {
+    /// The candidates of the peer in the order we try them in
+    endpoints: Vec<String>,
+    index: usize,
+    selected_at: SystemTime,
+}
+
+/// Chooses which of their endpoints to use for each peer
+///
+/// Candidates are tried in the order of their priority, skipping those of an IP family we can't
+/// reach. If we don't see a handshake with a peer within the handshake timeout after choosing an
+/// endpoint or after the last handshake, we fall back to the next one. After the last candidate,
+/// we start over with the first one so that we switch back once it becomes reachable again.
+///
+/// Peers we don't have a keepalive with stick to their current candidate as we can't tell whether
+/// it works without traffic, the same as in [`crate::relay::RelayFallback`].
+pub struct EndpointSelector {
+    handshake_timeout: Duration,
+    selections: HashMap<Pubkey, Selection>,
+}
+
+impl EndpointSelector {
+    pub fn new(handshake_timeout: Duration) -> Self {
+        Self {
+            handshake_timeout,
+            selections: HashMap::new(),
+        }
+    }
+
+    /// Set the endpoint of each peer to the candidate that should be used
+    ///
+    /// Peers in our own datacenter are reached via their datacenter endpoint if they have one.
+    /// `latest_handshakes` is `None` if we couldn't find out about handshakes, in which case we
+    /// always use the most preferred candidate.
+    pub fn select(
+        &mut self,
+        peers: HashSet<WgPeer>,
+        own_families: &HashSet<IpFamily>,
+        own_datacenter: Option<&str>,
+        keepalive_policy: KeepalivePolicy,
+        latest_handshakes: Option<&HashMap<Pubkey, SystemTime>>,
+    ) -> HashSet<WgPeer> {
+        self.selections
+            .retain(|public_key, _| peers.iter().any(|x| x.public_key == *public_key));
+
+        peers
+            .into_iter()
+            .map(|peer| {
+                let latest_handshakes =
+                    latest_handshakes.filter(|_| keepalive_policy.for_peer(&peer) != 0);
+                let endpoint =
+                    self.select_for_peer(&peer, own_families, own_datacenter, latest_handshakes);
+                WgPeer { endpoint, ..peer }
+            })
+            .collect()
+    }
+
+    fn select_for_peer(
+        &mut self,
+        peer: &WgPeer,
+        own_families: &HashSet<IpFamily>,
+        own_datacenter: Option<&str>,
+        latest_handshakes: Option<&HashMap<Pubkey, SystemTime>>,
+    ) -> String {
+        let mut candidates = peer.candidates_from(own_datacenter);
+        // This is a stable sort so that the primary endpoint wins among equal priorities.
+        candidates.sort_by_key(|x| x.priority);
+        let reachable = candidates
+            .iter()
+            .filter(|x| endpoint_family(&x.endpoint).is_none_or(|x| own_families.contains(&x)))
+            .map(|x| x.endpoint.clone())
+            .collect::<Vec<_>>();
+        // If we don't know our own families or the peer doesn't have an endpoint in any of them, it
+        // doesn't hurt to try anyway.
+        let endpoints = if reachable.is_empty() || own_families.is_empty() {
+            candidates.into_iter().map(|x| x.endpoint).collect()
+        } else {
+            reachable
+        };
+
+        let now = SystemTime::now();
+        let selection = self
+            .selections
+            .entry(peer.public_key)
+            .or_insert_with(|| Selection {
+                endpoints: vec![],
+                index: 0,
+                selected_at: now,
+            });
+        if selection.endpoints != endpoints {
+            *selection = Selection {
+                endpoints,
+                index: 0,
+                selected_at: now,
+            };
+        }
+
+        if let Some(latest_handshakes) = latest_handshakes {
+            // Only handshakes since we chose the current candidate count, and they need to keep
+            // coming so that we notice when a candidate that used to work stops working.
+            let since = latest_handshakes
+                .get(&peer.public_key)
+                .map_or(selection.selected_at, |x| selection.selected_at.max(*x));
+            let timed_out = now
+                .duration_since(since)
+                .is_ok_and(|x| x >= self.handshake_timeout);
+            if timed_out && selection.endpoints.len() > 1 {
+                let next_index = (selection.index + 1) % selection.endpoints.len();
+                info!(
+                    "No handshake with peer {} via {}, trying {}",
+                    peer.public_key.to_base64_urlsafe(),
+                    selection.endpoints[selection.index],
+                    selection.endpoints[next_index]
+                );
+                selection.index = next_index;
+                selection.selected_at = now;
+            }
+        }
+
+        selection.endpoints[selection.index].clone()
+    }
+}
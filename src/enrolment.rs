@@ -0,0 +1,267 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{ensure, Context, Result};
+use base64::prelude::{Engine as _, BASE64_URL_SAFE_NO_PAD};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+use wireguard_keys::Pubkey;
+
+use crate::signing::{AuthorityKey, ClaimKey, MeshAuthority};
+
+/// # A one-time token allowing a new node to join the mesh
+///
+/// Tokens are created by an operator and stored in Consul under the hash of their secret so that
+/// reading Consul doesn't reveal usable tokens. A new node presents the secret on its first start
+/// to claim the token, see [`JoinClaim`]. With `--require-enrolment`, nodes only accept peers that
+/// claimed a token. Deleting a claimed token revokes the node again.
+///
+/// Nodes never write tokens, so `join-tokens/` can be restricted to operators via Consul ACLs.
+/// With a mesh authority, tokens additionally need to be signed by it.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct JoinToken {
+    /// Until when the token can be claimed, in RFC 3339 format.
+    pub expires_at: String,
+
+    /// Address the node claiming the token gets.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<IpAddr>,
+
+    /// Public half of the key derived from the secret which claims are signed with.
+    pub claim_key: String,
+
+    /// Base64 encoded Ed25519 signature of the mesh authority over the other fields.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+impl JoinToken {
+    pub fn new(secret: &str, ttl: Duration, address: Option<IpAddr>) -> Self {
+        Self {
+            expires_at: humantime::format_rfc3339_seconds(SystemTime::now() + ttl).to_string(),
+            address,
+            claim_key: ClaimKey::from_secret(secret).public_key(),
+            signature: None,
+        }
+    }
+
+    /// Sign the token with the secret key of the mesh authority
+    pub fn sign(self, authority_key: &AuthorityKey) -> Result<Self> {
+        let mut value = serde_json::to_value(self)?;
+        authority_key.sign(&mut value)?;
+        Ok(serde_json::from_value(value)?)
+    }
+
+    pub fn is_expired(&self) -> Result<bool> {
+        Ok(self.expires_at()? < SystemTime::now())
+    }
+
+    fn expires_at(&self) -> Result<SystemTime> {
+        humantime::parse_rfc3339(&self.expires_at).context("Join token has an invalid expiry")
+    }
+}
+
+/// # Claim of a join token by a node
+///
+/// Claims are stored under `claims/` with the ID of the token. They're signed with the key derived
+/// from the secret of the token which proves that the node knew the secret.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct JoinClaim {
+    /// The claim key of the token that was claimed.
+    pub claim_key: String,
+
+    /// The node that claimed the token.
+    pub public_key: Pubkey,
+
+    /// When the token was claimed, in RFC 3339 format.
+    pub claimed_at: String,
+
+    /// Base64 encoded Ed25519 signature over the other fields.
+    pub signature: String,
+}
+
+impl JoinClaim {
+    pub fn new(secret: &str, public_key: Pubkey) -> Result<Self> {
+        let claim_key = ClaimKey::from_secret(secret);
+        let mut value = serde_json::json!({
+            "claim_key": claim_key.public_key(),
+            "public_key": public_key,
+            "claimed_at": humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        });
+        claim_key.sign(&mut value)?;
+        Ok(serde_json::from_value(value)?)
+    }
+
+    /// Verify that the claim was made for the given token before it expired
+    pub fn verify(&self, token: &JoinToken) -> Result<()> {
+        ensure!(
+            self.claim_key == token.claim_key,
+            "Claim is for another token"
+        );
+        ClaimKey::verify(&token.claim_key, &serde_json::to_value(self)?)?;
+        let claimed_at =
+            humantime::parse_rfc3339(&self.claimed_at).context("Claim has an invalid time")?;
+        ensure!(
+            claimed_at <= token.expires_at()?,
+            "Token was claimed after it expired at {}",
+            token.expires_at
+        );
+        Ok(())
+    }
+}
+
+/// # Decision of an operator about a node
+///
+/// With `--require-approval`, new nodes publish their config under `pending/` until an operator
+/// approves or rejects them via `wiresmith approve` or `wiresmith reject`. Decisions are stored
+/// under `approvals/`.
+///
+/// Nodes only need to read `approvals/`, so writing it should be restricted to operators via
+/// Consul ACLs. With a mesh authority, decisions additionally need to be signed by it.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Approval {
+    pub public_key: Pubkey,
+
+    pub approved: bool,
+
+    /// When the decision was made, in RFC 3339 format.
+    pub decided_at: String,
+
+    /// Base64 encoded Ed25519 signature of the mesh authority over the other fields.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+impl Approval {
+    pub fn new(public_key: Pubkey, approved: bool) -> Self {
+        Self {
+            public_key,
+            approved,
+            decided_at: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            signature: None,
+        }
+    }
+
+    /// Sign the decision with the secret key of the mesh authority
+    pub fn sign(self, authority_key: &AuthorityKey) -> Result<Self> {
+        let mut value = serde_json::to_value(self)?;
+        authority_key.sign(&mut value)?;
+        Ok(serde_json::from_value(value)?)
+    }
+}
+
+/// Whether a node may take part in the mesh
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum EnrolmentState {
+    /// The node claimed a join token or was approved by an operator.
+    Enrolled,
+
+    /// The node is waiting for an operator to approve it.
+    Pending,
+
+    /// An operator rejected the node.
+    Rejected,
+}
+
+/// Claimed join tokens and approval decisions of all nodes
+#[derive(Clone, Debug, Default)]
+pub struct Enrolments {
+    pub claimed: HashSet<Pubkey>,
+    pub approvals: HashMap<Pubkey, bool>,
+}
+
+impl Enrolments {
+    /// Collect the enrolments from the values stored in Consul
+    ///
+    /// Only valid claims of existing tokens count. If a token has several valid claims for
+    /// different nodes, its secret was used more than once and none of them count. With a mesh
+    /// authority, only tokens and approvals signed by it count.
+    pub fn verify(
+        tokens: &[JoinToken],
+        claims: &[JoinClaim],
+        approvals: &[Approval],
+        authority: Option<&MeshAuthority>,
+    ) -> Self {
+        let tokens = tokens
+            .iter()
+            .filter(|token| is_signed(token, authority, "join token"))
+            .map(|token| (token.claim_key.as_str(), token))
+            .collect::<HashMap<_, _>>();
+
+        let mut claimed = HashMap::<&str, HashSet<Pubkey>>::new();
+        for claim in claims {
+            let Some(token) = tokens.get(claim.claim_key.as_str()) else {
+                continue;
+            };
+            match claim.verify(token) {
+                Ok(()) => {
+                    claimed
+                        .entry(&token.claim_key)
+                        .or_default()
+                        .insert(claim.public_key);
+                }
+                Err(err) => warn!(
+                    "Ignoring claim of a join token by {}: {err:#}",
+                    claim.public_key.to_base64_urlsafe()
+                ),
+            }
+        }
+
+        Self {
+            claimed: claimed
+                .into_values()
+                .filter(|x| {
+                    let unique = x.len() == 1;
+                    if !unique {
+                        warn!("Ignoring join token that was claimed by {} nodes", x.len());
+                    }
+                    unique
+                })
+                .flatten()
+                .collect(),
+            approvals: approvals
+                .iter()
+                .filter(|x| is_signed(x, authority, "approval"))
+                .map(|x| (x.public_key, x.approved))
+                .collect(),
+        }
+    }
+
+    /// Get the state of a node
+    ///
+    /// A rejection wins over a claimed join token so that operators can revoke any node.
+    pub fn state(&self, public_key: &Pubkey) -> EnrolmentState {
+        match self.approvals.get(public_key) {
+            Some(true) => EnrolmentState::Enrolled,
+            Some(false) => EnrolmentState::Rejected,
+            None if self.claimed.contains(public_key) => EnrolmentState::Enrolled,
+            None => EnrolmentState::Pending,
+        }
+    }
+}
+
+/// Whether a value was signed by the mesh authority, if there is one
+fn is_signed<T: Serialize>(value: &T, authority: Option<&MeshAuthority>, kind: &str) -> bool {
+    let Some(authority) = authority else {
+        return true;
+    };
+    serde_json::to_value(value)
+        .map_err(Into::into)
+        .and_then(|x| authority.verify(&x))
+        .inspect_err(|err| warn!("Ignoring {kind} that isn't signed by the authority: {err:#}"))
+        .is_ok()
+}
+
+/// Generate the secret of a new join token
+pub fn generate_secret() -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>())
+}
+
+/// Get the ID under which a join token is stored in Consul
+pub fn token_id(secret: &str) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(secret.trim().as_bytes()))
+}
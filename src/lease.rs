@@ -0,0 +1,64 @@
+use std::{net::IpAddr, time::SystemTime};
+
+use anyhow::{ensure, Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use wireguard_keys::Pubkey;
+
+/// Stable identity of a node that survives reinstalls
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+pub enum LeaseIdentity {
+    /// The hostname of the node
+    Hostname,
+
+    /// The ID in /etc/machine-id
+    ///
+    /// This only survives a reinstall if the machine ID is kept or provisioned.
+    MachineId,
+}
+
+impl LeaseIdentity {
+    /// Determine the identity of the local node
+    pub fn resolve(&self, hostname: Option<&str>) -> Result<String> {
+        match self {
+            LeaseIdentity::Hostname => hostname
+                .map(str::to_string)
+                .context("Can't use the hostname as lease identity as it's unknown"),
+            LeaseIdentity::MachineId => {
+                let machine_id = std::fs::read_to_string("/etc/machine-id")
+                    .context("Couldn't read /etc/machine-id")?;
+                let machine_id = machine_id.trim();
+                ensure!(!machine_id.is_empty(), "/etc/machine-id is empty");
+                Ok(machine_id.to_string())
+            }
+        }
+    }
+}
+
+/// # Address pinned to the identity of a node
+///
+/// With `--lease-identity`, nodes record their address under `leases/<identity>`. When a node is
+/// reinstalled and generates a new key, it reclaims the address of its identity as long as no other
+/// node took it in the meantime.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct AddressLease {
+    pub identity: String,
+    pub address: IpAddr,
+
+    /// The node that last held the lease.
+    pub public_key: Pubkey,
+
+    /// When the lease was last recorded, in RFC 3339 format.
+    pub updated_at: String,
+}
+
+impl AddressLease {
+    pub fn new(identity: &str, address: IpAddr, public_key: Pubkey) -> Self {
+        Self {
+            identity: identity.to_string(),
+            address,
+            public_key,
+            updated_at: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        }
+    }
+}
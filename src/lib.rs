@@ -1,7 +1,20 @@
 use std::time::Duration;
 
+pub mod allocation;
+pub mod client_config;
 pub mod consul;
+pub mod endpoint;
+pub mod enrolment;
+pub mod lease;
+pub mod metadata;
 pub mod networkd;
+pub mod pmtu;
+pub mod policy;
+pub mod relay;
+pub mod signing;
+pub mod static_peers;
+pub mod stun;
+pub mod topology;
 pub mod wireguard;
 
 pub const CONSUL_TTL: Duration = Duration::from_secs(15);
@@ -1,16 +1,41 @@
 mod args;
 
-use std::{collections::HashSet, time::Duration};
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
 
 use anyhow::{bail, ensure, Context, Result};
-use args::CliArgs;
+use args::{CliArgs, Command, PeerCommand};
 use clap::Parser;
+use ipnet::IpNet;
+use pnet::datalink;
 use tokio::time::{interval, sleep};
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, error, info, trace};
+use tracing::{debug, error, info, trace, warn};
+use wireguard_keys::{Privkey, Pubkey};
 
 use wiresmith::{
-    consul::ConsulClient, networkd::NetworkdConfiguration, wireguard::WgPeer, CONSUL_TTL,
+    allocation::{
+        AddressAllocator, AddressUsage, AllocationStrategy, ADDRESS_USAGE_REFRESH_PERIOD,
+    },
+    client_config::ClientConfig,
+    consul::{ConsulClient, PeerState},
+    endpoint::{
+        self, format_endpoint, EndpointCandidate, EndpointSelector, DEFAULT_ENDPOINT_PRIORITY,
+    },
+    enrolment::{self, EnrolmentState, JoinToken},
+    lease::AddressLease,
+    metadata::NodeMetadata,
+    networkd::NetworkdConfiguration,
+    pmtu::{PathMtuDiscovery, DEFAULT_MAX_MTU, MIN_MTU},
+    policy::PeeringPolicy,
+    relay::RelayFallback,
+    signing::{AuthorityKey, Enrolment, MeshAuthority},
+    static_peers, stun, topology,
+    wireguard::{self, WgPeer},
+    CONSUL_TTL,
 };
 
 #[tokio::main]
@@ -44,32 +69,60 @@ async fn main() -> Result<()> {
             .init();
     };
 
-    if let Some(address) = args.address {
-        ensure!(
-            args.network.contains(&address),
-            "Address {address} is not part of network {}",
-            args.network
-        );
-    }
-
-    let consul_client = ConsulClient::new(
+    let mut consul_client = ConsulClient::new(
         args.consul_address.clone(),
         &args.consul_prefix,
         args.consul_token.as_deref(),
     )?;
+    let authority = match &args.authority_public_key {
+        Some(authority_public_key) => {
+            info!("Only accepting peers enrolled by the mesh authority");
+            let authority = MeshAuthority::from_file(authority_public_key).await?;
+            consul_client = consul_client.with_authority(authority.clone());
+            Some(authority)
+        }
+        None => None,
+    };
 
-    let endpoint_address = if let Some(endpoint_address) = &args.endpoint_address {
-        endpoint_address.clone()
-    } else if let Some(endpoint_interface) = &args.endpoint_interface {
-        // Find suitable IP on provided interface.
-        endpoint_interface
-            .ips
-            .first()
-            .context("No IPs on interface")?
-            .ip()
-            .to_string()
-    } else {
-        unreachable!("Should have been handled by arg parsing");
+    if let Some(command) = &args.command {
+        if let Some(authority_secret_key) = &args.authority_secret_key {
+            consul_client = consul_client
+                .with_authority_key(AuthorityKey::from_file(authority_secret_key).await?);
+        }
+        return run_command(&consul_client, &args, command).await;
+    }
+    let network = args.network.context("No network provided")?;
+
+    // Computed once so that the start time we publish stays the same across restarts of the inner
+    // loop.
+    let metadata = args.metadata();
+
+    // A join token might come with the address we're supposed to use.
+    let join_token = match &args.join_token {
+        Some(secret) => Some(
+            consul_client
+                .get_join_token(secret)
+                .await
+                .context("Couldn't read join token")?,
+        ),
+        None => None,
+    };
+    let address = args.address.or(join_token.as_ref().and_then(|x| x.address));
+    if let Some(address) = address {
+        ensure!(
+            network.contains(&address),
+            "Address {address} is not part of network {network}"
+        );
+    }
+
+    let lease_identity = args
+        .lease_identity
+        .map(|x| x.resolve(metadata.hostname.as_deref()))
+        .transpose()?;
+
+    let static_peers = match &args.static_peers {
+        Some(static_peers) => static_peers::from_file(static_peers).await?,
+        None => HashSet::new(),
     };
 
     info!("Getting existing peers from Consul");
@@ -81,33 +134,152 @@ async fn main() -> Result<()> {
         debug!("Existing peers:\n{:#?}", peers);
     }
 
-    // Check whether we can find and parse an existing config.
-    let networkd_config = if let Ok(config) =
-        NetworkdConfiguration::from_config(&args.networkd_dir, &args.wg_interface).await
-    {
+    // Check whether we can find and parse an existing config. If there is one we can't parse, we
+    // must not replace it as that would silently change the key and address of the node.
+    let existing_config =
+        NetworkdConfiguration::from_config(&args.networkd_dir, &args.wg_interface).await;
+    if let Err(err) = &existing_config {
+        let netdev_path = args
+            .networkd_dir
+            .join(&args.wg_interface)
+            .with_extension("netdev");
+        if netdev_path.exists() {
+            bail!("Couldn't parse existing config at {netdev_path:?}: {err:#}");
+        }
+    }
+    let networkd_config = if let Ok(mut config) = existing_config {
         info!("Successfully loading existing systemd-networkd config");
+
+        // Options passed on the command line might differ from the ones in the existing config,
+        // in which case the new ones win.
+        let mut changed = config.link_options.update_from(&args.link_options());
+        if changed {
+            info!(
+                "Interface options changed, updating config to {:?}",
+                config.link_options
+            );
+        }
+
+        // The same goes for the network and port. If our address isn't part of the network
+        // anymore, we need a new one rather than keep publishing the stale one.
+        let current_address = config.wg_address.addr();
+        let address = if network.contains(&current_address) {
+            current_address
+        } else {
+            warn!(
+                "Address {current_address} is not part of network {network}, allocating a new one"
+            );
+            peer_address(
+                &consul_client,
+                &args,
+                address,
+                Some(network),
+                config.public_key,
+                metadata.hostname.as_deref(),
+                &static_peers,
+            )
+            .await?
+        };
+        if config.migrate(address, network, args.wg_port)? {
+            info!(
+                "Network or port changed, migrating config to address {} and port {}",
+                config.wg_address, config.wg_port
+            );
+            changed = true;
+        }
+
+        if changed {
+            config
+                .write_config(&args.networkd_dir, args.keepalive_policy())
+                .await?;
+        }
         config
     } else {
         info!("No existing WireGuard configuration found on system, creating a new one");
 
-        // If we can't find or parse an existing config, we'll just generate a new one.
+        // If we can't find an existing config, we'll just generate a new one.
+        let private_key = Privkey::generate();
+        let address = match (address, &lease_identity) {
+            (None, Some(identity)) => {
+                leased_address(&consul_client, identity, network, &static_peers).await?
+            }
+            _ => address,
+        };
+        let address = peer_address(
+            &consul_client,
+            &args,
+            address,
+            Some(network),
+            private_key.pubkey(),
+            metadata.hostname.as_deref(),
+            &static_peers,
+        )
+        .await?;
         let networkd_config = NetworkdConfiguration::new(
-            args.address,
-            args.network,
+            private_key,
+            address,
+            network,
             args.wg_port,
             &args.wg_interface,
-            peers,
+            args.link_options(),
+            static_peers::merge(peers, &static_peers),
         )?;
         networkd_config
-            .write_config(&args.networkd_dir, args.keepalive)
+            .write_config(&args.networkd_dir, args.keepalive_policy())
             .await?;
         info!("Our new config is:\n{:#?}", networkd_config);
         networkd_config
     };
 
+    if let Some(identity) = &lease_identity {
+        let lease = AddressLease::new(
+            identity,
+            networkd_config.wg_address.addr(),
+            networkd_config.public_key,
+        );
+        consul_client
+            .put_lease(&lease)
+            .await
+            .context("Couldn't record address lease")?;
+    }
+
+    let enrolment = match &args.enrolment {
+        Some(enrolment) => Some(Enrolment::from_file(enrolment).await?),
+        None => None,
+    };
+    if let Some(authority) = &authority {
+        check_enrolment(
+            authority,
+            enrolment.as_ref(),
+            networkd_config.public_key,
+            networkd_config.wg_address.addr(),
+        );
+    }
+
+    if let Some(secret) = &args.join_token {
+        consul_client
+            .claim_join_token(secret, networkd_config.public_key)
+            .await
+            .context("Couldn't claim join token")?;
+        info!("Enrolled this node with the join token");
+    }
+
+    // The port the NAT maps WireGuard traffic to can only be discovered as long as the kernel
+    // doesn't hold on to the listen port, i.e. before the interface is brought up.
+    let stun_port = match &args.endpoint_stun {
+        Some(endpoint_stun) => stun_port(endpoint_stun, args.wg_port).await?,
+        None => args.wg_port,
+    };
+
     info!("Restarting systemd-networkd");
     NetworkdConfiguration::restart().await?;
 
+    let local_node = LocalNode {
+        enrolment,
+        stun_port,
+        metadata,
+    };
+
     let mut interval = interval(Duration::from_secs(5));
     interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
 
@@ -125,16 +297,20 @@ async fn main() -> Result<()> {
             _ = interval.tick() => {},
         };
 
-        if let Err(err) = inner_loop(
+        match inner_loop(
             &consul_client,
-            &endpoint_address,
             &networkd_config,
+            &local_node,
+            &static_peers,
             &args,
             top_level_token.child_token(),
         )
         .await
         {
-            error!("Inner loop exited with an error: {err:?}");
+            Ok(()) => {}
+            // Retrying won't change the mind of the operator.
+            Err(err) if err.is::<Rejected>() => return Err(err),
+            Err(err) => error!("Inner loop exited with an error: {err:?}"),
         }
 
         if top_level_token.is_cancelled() {
@@ -148,24 +324,472 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+/// Run a subcommand instead of the daemon
+async fn run_command(
+    consul_client: &ConsulClient,
+    args: &CliArgs,
+    command: &Command,
+) -> Result<()> {
+    match command {
+        Command::CreateJoinToken { ttl, address } => {
+            let secret = enrolment::generate_secret();
+            let mut token = JoinToken::new(&secret, *ttl, *address);
+            if args.authority_secret_key.is_some() {
+                token = token.sign(&authority_key(args).await?)?;
+            }
+            consul_client
+                .create_join_token(&secret, &token)
+                .await
+                .context("Couldn't create join token")?;
+            println!("{secret}");
+        }
+        Command::Pending => {
+            let enrolments = consul_client.get_enrolments().await?;
+            for peer in consul_client.get_pending_peers().await? {
+                if enrolments.state(&peer.public_key) == EnrolmentState::Pending {
+                    println!(
+                        "{} {} {}",
+                        peer.public_key.to_base64_urlsafe(),
+                        peer.address,
+                        peer.metadata.description().unwrap_or_default()
+                    );
+                }
+            }
+        }
+        Command::Approve { public_key } => {
+            consul_client
+                .decide_approval(*public_key, true)
+                .await
+                .context("Couldn't approve node")?;
+        }
+        Command::Reject { public_key } => {
+            consul_client
+                .decide_approval(*public_key, false)
+                .await
+                .context("Couldn't reject node")?;
+        }
+        Command::ExportClient {
+            name,
+            address,
+            network,
+            hub,
+            keepalive,
+        } => {
+            let private_key = Privkey::generate();
+            let address = peer_address(
+                consul_client,
+                args,
+                *address,
+                Some(*network),
+                private_key.pubkey(),
+                Some(name),
+                &HashSet::new(),
+            )
+            .await?;
+            let client_config = ClientConfig::new(
+                name,
+                private_key,
+                IpNet::new(address, network.prefix_len())?,
+                &consul_client.get_peers().await?,
+                *hub,
+                *keepalive,
+            )?;
+            let wgpeer = client_config.wg_peer();
+            consul_client
+                .add_external_peer(&wgpeer, enrol_external_peer(args, &wgpeer).await?)
+                .await
+                .context("Couldn't register device")?;
+            print!("{}", client_config.to_wg_quick());
+            eprintln!("{}", client_config.to_qr_code()?);
+        }
+        Command::AuthorityPublicKey => {
+            println!("{}", authority_key(args).await?.authority().to_base64());
+        }
+        Command::SignEnrolment {
+            public_key,
+            address,
+        } => {
+            let enrolment = authority_key(args).await?.enrol(*public_key, *address)?;
+            println!("{}", serde_json::to_string_pretty(&enrolment)?);
+        }
+        Command::Peer {
+            command:
+                PeerCommand::Add {
+                    public_key,
+                    endpoint,
+                    wg_port,
+                    address,
+                    network,
+                    keepalive,
+                },
+        } => {
+            let address = peer_address(
+                consul_client,
+                args,
+                *address,
+                *network,
+                *public_key,
+                None,
+                &HashSet::new(),
+            )
+            .await?;
+            let mut wgpeer = WgPeer::new(
+                *public_key,
+                &endpoint::with_default_port(endpoint, *wg_port),
+                address,
+            );
+            wgpeer.keepalive = *keepalive;
+            consul_client
+                .add_external_peer(&wgpeer, enrol_external_peer(args, &wgpeer).await?)
+                .await
+                .context("Couldn't add peer")?;
+            println!("{address}");
+        }
+        Command::Peer {
+            command: PeerCommand::Remove { public_key },
+        } => {
+            consul_client
+                .remove_external_peer(*public_key)
+                .await
+                .context("Couldn't remove peer")?;
+        }
+    }
+    Ok(())
+}
+
+/// Warn if other nodes will reject us as we aren't enrolled by the mesh authority
+fn check_enrolment(
+    authority: &MeshAuthority,
+    enrolment: Option<&Enrolment>,
+    public_key: Pubkey,
+    address: IpAddr,
+) {
+    let hint = format!(
+        "enrol this node with `wiresmith sign-enrolment --public-key {} --address {address}` and pass the result via --enrolment",
+        public_key.to_base64_urlsafe()
+    );
+    match enrolment {
+        None => warn!("Other nodes will reject us as we're not enrolled, {hint}"),
+        Some(enrolment) => {
+            if let Err(err) = authority.verify_enrolment(enrolment, public_key, address) {
+                warn!("Other nodes will reject us as our enrolment is invalid ({err:#}), {hint}");
+            }
+        }
+    }
+}
+
+/// Read the secret key of the mesh authority
+async fn authority_key(args: &CliArgs) -> Result<AuthorityKey> {
+    let path = args
+        .authority_secret_key
+        .as_ref()
+        .context("--authority-secret-key is required")?;
+    AuthorityKey::from_file(path).await
+}
+
+/// Sign the enrolment of a peer registered via a subcommand
+///
+/// Without --authority-secret-key, the peer isn't enrolled which is fine unless nodes require
+/// enrolments via --authority-public-key.
+async fn enrol_external_peer(args: &CliArgs, wgpeer: &WgPeer) -> Result<Option<Enrolment>> {
+    if args.authority_secret_key.is_none() {
+        if args.authority_public_key.is_some() {
+            warn!("Not enrolling the peer as no --authority-secret-key was provided");
+        }
+        return Ok(None);
+    }
+    let enrolment = authority_key(args)
+        .await?
+        .enrol(wgpeer.public_key, wgpeer.address.addr())?;
+    Ok(Some(enrolment))
+}
+
+/// Pick the address of a new peer
+///
+/// If no address was provided, one is allocated from the network according to
+/// --allocation-strategy. Addresses of pending and static peers aren't handed out either.
+async fn peer_address(
+    consul_client: &ConsulClient,
+    args: &CliArgs,
+    address: Option<IpAddr>,
+    network: Option<IpNet>,
+    public_key: Pubkey,
+    hostname: Option<&str>,
+    static_peers: &HashSet<WgPeer>,
+) -> Result<IpAddr> {
+    match (address, network) {
+        (Some(address), Some(network)) => {
+            ensure!(
+                network.contains(&address),
+                "Address {address} is not part of network {network}"
+            );
+            Ok(address)
+        }
+        (Some(address), None) => Ok(address),
+        (None, Some(network)) => {
+            let allocator = AddressAllocator {
+                strategy: args
+                    .allocation_strategy
+                    .unwrap_or_else(|| AllocationStrategy::for_network(&network)),
+                quarantine: args.address_quarantine,
+                occupied: occupied_addresses(consul_client, static_peers).await?,
+                usage: consul_client.get_address_usage().await?,
+                reserved: args
+                    .reserved
+                    .iter()
+                    .copied()
+                    .chain(consul_client.get_reservations().await?)
+                    .collect(),
+            };
+            allocator
+                .allocate(&network, &public_key, hostname)
+                .context("Couldn't find usable address")
+        }
+        (None, None) => unreachable!("Should have been handled by arg parsing"),
+    }
+}
+
+/// Get the addresses of all active, pending and static peers
+async fn occupied_addresses(
+    consul_client: &ConsulClient,
+    static_peers: &HashSet<WgPeer>,
+) -> Result<HashSet<IpAddr>> {
+    Ok(consul_client
+        .get_peers()
+        .await?
+        .into_iter()
+        .chain(consul_client.get_pending_peers().await?)
+        .chain(static_peers.iter().cloned())
+        .map(|x| x.address.addr())
+        .collect())
+}
+
+/// Get the address leased to our identity if we can reclaim it
+///
+/// The quarantine doesn't apply as the address was last used by ourselves.
+async fn leased_address(
+    consul_client: &ConsulClient,
+    identity: &str,
+    network: IpNet,
+    static_peers: &HashSet<WgPeer>,
+) -> Result<Option<IpAddr>> {
+    let Some(lease) = consul_client.get_lease(identity).await? else {
+        return Ok(None);
+    };
+    if !network.contains(&lease.address) {
+        info!(
+            "Address {} leased to {identity} is not part of network {network}, ignoring it",
+            lease.address
+        );
+        return Ok(None);
+    }
+    if occupied_addresses(consul_client, static_peers)
+        .await?
+        .contains(&lease.address)
+    {
+        warn!(
+            "Address {} leased to {identity} is used by another peer, allocating a new one",
+            lease.address
+        );
+        return Ok(None);
+    }
+    info!("Reclaiming address {} leased to {identity}", lease.address);
+    Ok(Some(lease.address))
+}
+
+/// Discover the port the NAT maps the WireGuard listen port to via STUN
+///
+/// If the listen port is in use already, e.g. because the interface is still up from before we
+/// were restarted, we assume that the NAT preserves it.
+async fn stun_port(endpoint_stun: &str, listen_port: u16) -> Result<u16> {
+    let endpoint = stun::discover_endpoint(endpoint_stun, listen_port)
+        .await
+        .context("Couldn't discover public endpoint via STUN")?;
+    match endpoint {
+        Some(endpoint) => {
+            info!("NAT maps our listen port {listen_port} to {endpoint}");
+            Ok(endpoint.port())
+        }
+        None => {
+            warn!(
+                "Listen port {listen_port} is already in use so its NAT mapping can't be discovered, assuming the NAT preserves it"
+            );
+            Ok(listen_port)
+        }
+    }
+}
+
+/// Determine the endpoints other peers should use to reach us
+///
+/// Returns the primary endpoint as well as further candidates. These are the configured
+/// candidates and, with --endpoint-interface, possibly an endpoint in the other IP family. With
+/// --endpoint-stun, the endpoint uses the given port discovered on startup.
+async fn own_endpoints(args: &CliArgs, stun_port: u16) -> Result<(String, Vec<EndpointCandidate>)> {
+    let mut endpoints = detect_endpoints(args, stun_port).await?;
+    let primary = endpoints.remove(0);
+    let candidates = endpoints
+        .into_iter()
+        .map(|endpoint| EndpointCandidate {
+            endpoint,
+            label: None,
+            priority: DEFAULT_ENDPOINT_PRIORITY,
+        })
+        .chain(args.endpoint_candidates.iter().map(|x| EndpointCandidate {
+            endpoint: endpoint::with_default_port(&x.endpoint, args.wg_port),
+            ..x.clone()
+        }))
+        .collect();
+    Ok((primary, candidates))
+}
+
+/// Detect the endpoints under which we're reachable
+///
+/// The first endpoint is the primary one. With --endpoint-interface, there might be another one
+/// in the other IP family.
+#[tracing::instrument(skip_all)]
+async fn detect_endpoints(args: &CliArgs, stun_port: u16) -> Result<Vec<String>> {
+    if let Some(endpoint_address) = &args.endpoint_address {
+        Ok(vec![format_endpoint(endpoint_address, args.wg_port)])
+    } else if let Some(endpoint_interface) = &args.endpoint_interface {
+        // Look up the interface again since its addresses might have changed since we started,
+        // e.g. due to a DHCP renewal.
+        let endpoint_interface = datalink::interfaces()
+            .into_iter()
+            .find(|x| x.name == endpoint_interface.name)
+            .context(format!("Interface {} disappeared", endpoint_interface.name))?;
+
+        // Find suitable IPs on provided interface.
+        let ips = endpoint_interface
+            .ips
+            .iter()
+            .map(|x| x.ip())
+            .collect::<Vec<_>>();
+        let unstable_ips = endpoint::unstable_ipv6_addresses(&endpoint_interface.name)?;
+        let endpoint_ips = endpoint::select_endpoint_ips(&ips, args.endpoint_family, &unstable_ips);
+        ensure!(
+            !endpoint_ips.is_empty(),
+            "No usable IPs on interface {}",
+            endpoint_interface.name
+        );
+        Ok(endpoint_ips
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, args.wg_port).to_string())
+            .collect())
+    } else if let Some(endpoint_stun) = &args.endpoint_stun {
+        // We can't send from the listen port once WireGuard holds on to it, so only the address is
+        // discovered here and the port is assumed to still be mapped as it was on startup.
+        let address = stun::discover_address(endpoint_stun)
+            .await
+            .context("Couldn't discover public address via STUN")?;
+        Ok(vec![SocketAddr::new(address, stun_port).to_string()])
+    } else {
+        unreachable!("Should have been handled by arg parsing");
+    }
+}
+
+/// An operator rejected this node
+#[derive(Debug)]
+struct Rejected;
+
+impl std::fmt::Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "This node was rejected by an operator")
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+/// What we know about this node that doesn't change when the inner loop restarts
+struct LocalNode {
+    enrolment: Option<Enrolment>,
+
+    /// The port the NAT maps WireGuard traffic to
+    stun_port: u16,
+
+    metadata: NodeMetadata,
+}
+
 #[tracing::instrument(skip_all)]
 async fn inner_loop(
     consul_client: &ConsulClient,
-    endpoint_address: &str,
     networkd_config: &NetworkdConfiguration,
+    local_node: &LocalNode,
+    static_peers: &HashSet<WgPeer>,
     args: &CliArgs,
     token: CancellationToken,
 ) -> Result<()> {
+    // Until an operator approved us, we only publish our config as pending.
+    let require_enrolment = args.require_enrolment || args.require_approval;
+    let enrolment_state = if args.require_approval {
+        consul_client
+            .get_enrolments()
+            .await
+            .context("Can't fetch enrolments from Consul")?
+            .state(&networkd_config.public_key)
+    } else {
+        EnrolmentState::Enrolled
+    };
+    let peer_state = match enrolment_state {
+        EnrolmentState::Enrolled => PeerState::Active,
+        EnrolmentState::Pending => {
+            info!(
+                "Waiting for approval, approve this node with `wiresmith approve {}`",
+                networkd_config.public_key.to_base64_urlsafe()
+            );
+            PeerState::Pending
+        }
+        EnrolmentState::Rejected => bail!(Rejected),
+    };
+
     // Create a Consul session to hold the config KV lock under.
     let consul_session = consul_client
-        .create_session(networkd_config.public_key, token.clone())
+        .create_session(
+            networkd_config.public_key,
+            peer_state,
+            local_node.enrolment.clone(),
+            token.clone(),
+        )
         .await?;
 
-    let own_wg_peer = WgPeer::new(
+    let (endpoint, endpoint_candidates) = own_endpoints(args, local_node.stun_port).await?;
+    let mut endpoint_checked_at = Instant::now();
+    let mut address_usage_updated_at: Option<Instant> = None;
+
+    let mut own_wg_peer = WgPeer::new(
         networkd_config.public_key,
-        &format!("{endpoint_address}:{}", args.wg_port),
+        &endpoint,
         networkd_config.wg_address.addr(),
     );
+    own_wg_peer.endpoint_candidates = endpoint_candidates;
+    own_wg_peer.datacenter_endpoint = args
+        .endpoint_datacenter_address
+        .as_ref()
+        .map(|x| endpoint::with_default_port(x, args.wg_port));
+
+    let own_datacenter = consul_client
+        .local_datacenter()
+        .await
+        .context("Couldn't get the datacenter of the Consul agent")?;
+    own_wg_peer.keepalive = args.advertise_keepalive;
+    own_wg_peer.role = args.role;
+    own_wg_peer.tags = args.tags();
+    own_wg_peer.relay = args.relay;
+    own_wg_peer.metadata = local_node.metadata.clone();
+
+    let file_policy = match &args.policy_file {
+        Some(policy_file) => Some(PeeringPolicy::from_file(policy_file).await?),
+        None => None,
+    };
+
+    let mut consul_policy = None;
+
+    let mut endpoint_selector = EndpointSelector::new(args.endpoint_handshake_timeout);
+    let mut relay_fallback = RelayFallback::new(args.relay_timeout);
+
+    let mut pmtu_discovery = args.pmtu_discovery.then(|| {
+        own_wg_peer.mtu = networkd_config.link_options.mtu;
+        PathMtuDiscovery::new(args.mtu.unwrap_or(DEFAULT_MAX_MTU), args.pmtu_probe_period)
+    });
 
     info!(
         "Submitting own WireGuard peer config to Consul:\n{:#?}",
@@ -203,34 +827,136 @@ async fn inner_loop(
     info!("Wrote own WireGuard peer config to Consul");
 
     // Enter main loop which periodically checks for updates to the list of WireGuard peers.
+    let (wg_address, wg_port) = (networkd_config.wg_address, networkd_config.wg_port);
     loop {
         trace!("Checking Consul for peer updates");
         let peers = consul_client
             .get_peers()
             .await
             .context("Can't fetch existing peers from Consul")?;
-        let mut networkd_config =
-            NetworkdConfiguration::from_config(&args.networkd_dir, &args.wg_interface)
-                .await
-                .context("Couldn't load existing NetworkdConfiguration from disk")?;
+        let mut networkd_config = NetworkdConfiguration::reconcile(
+            &args.networkd_dir,
+            &args.wg_interface,
+            wg_address,
+            wg_port,
+        )
+        .await
+        .context("Couldn't load existing NetworkdConfiguration from disk")?;
 
-        // Exclude own peer config.
+        // Exclude own peer config, peers that weren't enrolled if we require that and the peers
+        // we're not supposed to peer with according to the peering policy and our topology.
         let peers_without_own_config = peers
             .iter()
             .filter(|&x| x.public_key != networkd_config.public_key)
             .cloned()
             .collect::<HashSet<WgPeer>>();
+        let peers_without_own_config = if require_enrolment {
+            let enrolments = consul_client
+                .get_enrolments()
+                .await
+                .context("Can't fetch enrolments from Consul")?;
+
+            // Once an operator decided about us, publish our config again in the right place.
+            let new_state = enrolments.state(&networkd_config.public_key);
+            if enrolment_state == EnrolmentState::Pending && new_state != enrolment_state {
+                info!("Approval state changed to {new_state:?}");
+                break;
+            }
+
+            peers_without_own_config
+                .into_iter()
+                .filter(|x| {
+                    let is_enrolled = enrolments.state(&x.public_key) == EnrolmentState::Enrolled;
+                    if !is_enrolled {
+                        debug!("Ignoring peer {} as it isn't enrolled", x.name());
+                    }
+                    is_enrolled
+                })
+                .collect()
+        } else {
+            peers_without_own_config
+        };
+        // A broken policy in Consul must not take down every node, so we stick to the last one we
+        // could read.
+        let policy = match &file_policy {
+            Some(policy) => Some(policy.clone()),
+            None => {
+                match consul_client.get_policy().await {
+                    Ok(policy) => consul_policy = policy,
+                    Err(err) => warn!(
+                        "Can't fetch peering policy from Consul, keeping the last one: {err:?}"
+                    ),
+                }
+                consul_policy.clone()
+            }
+        };
+        let peers_without_own_config = match policy {
+            Some(policy) => policy.filter(&own_wg_peer.tags, peers_without_own_config),
+            None => peers_without_own_config,
+        };
+        let peers_without_own_config = args.topology.peers_for(
+            args.role,
+            networkd_config.wg_address,
+            peers_without_own_config,
+        );
 
-        // If there is a mismatch, write a new networkd configuration.
+        // Choose which endpoint to use for each peer, based on the IP families we can be reached
+        // in ourselves, whether the peer is in our datacenter and whether we managed to get a
+        // handshake via the current endpoint.
+        let own_families = own_wg_peer
+            .candidates()
+            .iter()
+            .filter_map(|x| endpoint::endpoint_family(&x.endpoint))
+            .collect::<HashSet<_>>();
+        let latest_handshakes = wireguard::latest_handshakes(&args.wg_interface)
+            .await
+            .inspect_err(|err| debug!("Couldn't get latest handshakes: {err:?}"))
+            .ok();
+        let peers_without_own_config = endpoint_selector.select(
+            peers_without_own_config,
+            &own_families,
+            Some(&own_datacenter),
+            args.keepalive_policy(),
+            latest_handshakes.as_ref(),
+        );
+
+        // Route peers we can't reach directly via a relay.
+        let peers_without_own_config = relay_fallback.apply(
+            peers_without_own_config,
+            args.keepalive_policy(),
+            latest_handshakes.as_ref(),
+        );
+
+        // Peers that are only connected to a hub are reached via that hub.
+        let peers_without_own_config =
+            topology::route_via_hubs(networkd_config.public_key, peers_without_own_config);
+
+        // Static peers are always configured, no matter what the rules above say.
+        let peers_without_own_config = static_peers::merge(peers_without_own_config, static_peers);
+
+        let current_public_keys = networkd_config
+            .peers
+            .iter()
+            .map(|x| x.public_key)
+            .collect::<HashSet<_>>();
+        let new_public_keys = peers_without_own_config
+            .iter()
+            .map(|x| x.public_key)
+            .collect::<HashSet<_>>();
         let additional_peers = peers_without_own_config
-            .difference(&networkd_config.peers)
+            .iter()
+            .filter(|x| !current_public_keys.contains(&x.public_key))
             .collect::<Vec<_>>();
         let deleted_peers = networkd_config
             .peers
-            .difference(&peers_without_own_config)
+            .iter()
+            .filter(|x| !new_public_keys.contains(&x.public_key))
             .collect::<Vec<_>>();
         if !additional_peers.is_empty() {
             info!("Found {} new peer(s) in Consul", additional_peers.len());
+            for peer in &additional_peers {
+                info!("New peer {}", peer.name());
+            }
             debug!("New peers: {:#?}", additional_peers);
         }
         if !deleted_peers.is_empty() {
@@ -238,13 +964,68 @@ async fn inner_loop(
             debug!("Deleted peers: {:#?}", deleted_peers);
         }
 
-        if !additional_peers.is_empty() || !deleted_peers.is_empty() {
-            networkd_config.peers = peers_without_own_config;
-            networkd_config
-                .write_config(&args.networkd_dir, args.keepalive)
-                .await
-                .context("Couldn't write new NetworkdConfiguration")?;
+        if let Some(pmtu_discovery) = &mut pmtu_discovery {
+            let current_mtu = networkd_config.link_options.mtu.unwrap_or(MIN_MTU);
+            let mtu = pmtu_discovery
+                .safe_mtu(&peers_without_own_config, current_mtu)
+                .await;
+            if own_wg_peer.mtu != Some(mtu) {
+                info!("Setting MTU to {mtu}");
+                own_wg_peer.mtu = Some(mtu);
+                consul_session
+                    .update_config(&own_wg_peer)
+                    .await
+                    .context("Couldn't publish new MTU to Consul")?;
+            }
+            networkd_config.link_options.mtu = Some(mtu);
+        }
+
+        // Record that we're still using our address so that it's kept in quarantine for a while
+        // once we're gone.
+        if address_usage_updated_at.is_none_or(|x| x.elapsed() >= ADDRESS_USAGE_REFRESH_PERIOD) {
+            address_usage_updated_at = Some(Instant::now());
+            let usage = AddressUsage::new(own_wg_peer.public_key, own_wg_peer.address.addr());
+            if let Err(err) = consul_client.update_address_usage(&usage).await {
+                warn!("Couldn't record address usage: {err:?}");
+            }
+        }
+
+        // Our public endpoint might change at any time if it's not static, for instance when the
+        // endpoint interface gets a new address or the NAT we're behind changes its mapping. In
+        // that case we update our config in Consul while holding on to our lock.
+        if args.endpoint_address.is_none()
+            && endpoint_checked_at.elapsed() >= args.endpoint_refresh_period
+        {
+            endpoint_checked_at = Instant::now();
+            match own_endpoints(args, local_node.stun_port).await {
+                Ok((endpoint, endpoint_candidates))
+                    if endpoint != own_wg_peer.endpoint
+                        || endpoint_candidates != own_wg_peer.endpoint_candidates =>
+                {
+                    info!(
+                        "Public endpoint changed from {} to {endpoint}",
+                        own_wg_peer.endpoint
+                    );
+                    own_wg_peer.endpoint = endpoint;
+                    own_wg_peer.endpoint_candidates = endpoint_candidates;
+                    consul_session
+                        .update_config(&own_wg_peer)
+                        .await
+                        .context("Couldn't publish new endpoint to Consul")?;
+                }
+                Ok(_) => trace!("Public endpoint didn't change"),
+                Err(err) => error!("Couldn't check public endpoint: {err:?}"),
+            }
+        }
+
+        // Write the new networkd configuration. This is a no-op if nothing changed.
+        networkd_config.peers = peers_without_own_config;
+        let config_changed = networkd_config
+            .write_config(&args.networkd_dir, args.keepalive_policy())
+            .await
+            .context("Couldn't write new NetworkdConfiguration")?;
 
+        if config_changed {
             info!("Restarting systemd-networkd to apply new config");
             NetworkdConfiguration::restart()
                 .await
@@ -0,0 +1,97 @@
+use std::{collections::BTreeMap, time::SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+/// Informational metadata about a node to make it easier to map peers back to hosts
+///
+/// All fields are optional so that configs published by older versions can still be read.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct NodeMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub os: Option<String>,
+
+    /// Version of wiresmith the node is running.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+
+    /// When wiresmith was started on the node, in RFC 3339 format.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<String>,
+
+    /// Arbitrary labels provided via `--meta`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+}
+
+impl NodeMetadata {
+    /// Gather metadata about the local node
+    pub fn local(labels: BTreeMap<String, String>) -> Self {
+        Self {
+            hostname: hostname(),
+            os: os_name(),
+            version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            started_at: Some(humantime::format_rfc3339_seconds(SystemTime::now()).to_string()),
+            labels,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Human readable summary, e.g. `web-1 (Arch Linux, wiresmith 0.4.5, env=prod)`
+    ///
+    /// The start time is left out as the description ends up in the networkd config which would
+    /// otherwise change whenever the node restarts. Returns `None` if there is no metadata at all.
+    ///
+    /// Metadata is published by other nodes and the description ends up in comments of config
+    /// files, so control characters are replaced with spaces to keep it on a single line.
+    pub fn description(&self) -> Option<String> {
+        let details = [
+            self.os.clone(),
+            self.version.as_ref().map(|x| format!("wiresmith {x}")),
+        ]
+        .into_iter()
+        .flatten()
+        .chain(
+            self.labels
+                .iter()
+                .map(|(key, value)| format!("{key}={value}")),
+        )
+        .collect::<Vec<_>>();
+
+        let description = match (&self.hostname, details.is_empty()) {
+            (None, true) => None,
+            (None, false) => Some(details.join(", ")),
+            (Some(hostname), true) => Some(hostname.clone()),
+            (Some(hostname), false) => Some(format!("{hostname} ({})", details.join(", "))),
+        };
+        description.map(|x| {
+            x.chars()
+                .map(|c| if c.is_control() { ' ' } else { c })
+                .collect()
+        })
+    }
+}
+
+fn hostname() -> Option<String> {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+}
+
+/// Get the pretty name of the OS from os-release(5), falling back to the OS family
+fn os_name() -> Option<String> {
+    let os_release = std::fs::read_to_string("/etc/os-release")
+        .or_else(|_| std::fs::read_to_string("/usr/lib/os-release"))
+        .unwrap_or_default();
+    os_release
+        .lines()
+        .find_map(|x| x.strip_prefix("PRETTY_NAME="))
+        .map(|x| x.trim_matches('"').to_string())
+        .or_else(|| Some(std::env::consts::OS.to_string()))
+}
@@ -1,37 +1,207 @@
 use std::{
-    collections::HashSet, fmt, fs::Permissions, net::IpAddr, os::unix::prelude::PermissionsExt,
-    path::Path,
+    collections::HashSet, fmt, fs::Permissions, io::ErrorKind, net::IpAddr,
+    os::unix::prelude::PermissionsExt, path::Path,
 };
 
 use anyhow::{anyhow, Context, Result};
 use file_owner::set_group;
+use ini::Ini;
 use ipnet::IpNet;
 use tokio::{fs, process::Command};
 use wireguard_keys::{Privkey, Pubkey};
 
-use crate::wireguard::WgPeer;
+use crate::{
+    metadata::NodeMetadata,
+    policy::Tags,
+    wireguard::{KeepalivePolicy, WgPeer},
+};
+
+/// Read an existing networkd file
+///
+/// Returns `None` if the file doesn't exist yet.
+async fn read_existing_file(path: &Path) -> Result<Option<String>> {
+    match fs::read_to_string(path).await {
+        Ok(contents) => Ok(Some(contents)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).context(format!("Couldn't read existing config at {path:?}")),
+    }
+}
+
+/// Whether a line is a section header, returning the name of the section if so
+fn section_name(line: &str) -> Option<&str> {
+    line.trim().strip_prefix('[')?.strip_suffix(']')
+}
+
+/// Split a line into key and value unless it's a comment or doesn't contain a key
+fn key_value(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim();
+    if line.starts_with('#') || line.starts_with(';') {
+        return None;
+    }
+    let (key, value) = line.split_once('=')?;
+    Some((key.trim(), value.trim()))
+}
+
+/// A networkd file that we merge our changes into line by line
+///
+/// rust-ini would drop comments and replace all values of a key that may be given multiple times,
+/// such as an additional `Address=` added by an admin. We therefore only touch the first line of
+/// each key we manage and leave everything else exactly as it was.
+struct NetworkdFile {
+    lines: Vec<String>,
+}
+
+impl NetworkdFile {
+    /// Start from the lines of an existing file or from an empty file if there is none
+    fn new(contents: Option<&str>) -> Self {
+        Self {
+            lines: contents
+                .unwrap_or_default()
+                .lines()
+                .map(str::to_string)
+                .collect(),
+        }
+    }
+
+    /// Indices of the lines in the first section with the given name, excluding the header
+    fn section(&self, name: &str) -> Option<std::ops::Range<usize>> {
+        let header = self
+            .lines
+            .iter()
+            .position(|line| section_name(line) == Some(name))?;
+        let end = self.lines[header + 1..]
+            .iter()
+            .position(|line| section_name(line).is_some())
+            .map_or(self.lines.len(), |x| header + 1 + x);
+        Some(header + 1..end)
+    }
+
+    /// Index of the first line setting the key in the first section with the given name
+    fn key(&self, section: &str, key: &str) -> Option<usize> {
+        self.section(section)?
+            .find(|i| key_value(&self.lines[*i]).is_some_and(|(k, _)| k == key))
+    }
+
+    /// Set a key that is managed by us
+    ///
+    /// Only the first occurrence of the key is replaced and only if its value differs. A missing
+    /// key is added after the last entry of the section, a missing section at the end of the file.
+    fn set(&mut self, section: &str, key: &str, value: &str) {
+        let line = format!("{key}={value}");
+        if let Some(i) = self.key(section, key) {
+            if key_value(&self.lines[i]) != Some((key, value)) {
+                self.lines[i] = line;
+            }
+        } else if let Some(range) = self.section(section) {
+            let start = range.start;
+            let i = range
+                .rev()
+                .find(|i| !self.lines[*i].trim().is_empty())
+                .map_or(start, |i| i + 1);
+            self.lines.insert(i, line);
+        } else {
+            self.push_section(section, vec![line]);
+        }
+    }
+
+    /// Set a key unless the admin already set it
+    fn set_default(&mut self, section: &str, key: &str, value: &str) {
+        if self.key(section, key).is_none() {
+            self.set(section, key, value);
+        }
+    }
+
+    /// Remove all sections with the given name including their comments
+    fn remove_sections(&mut self, name: &str) {
+        while let Some(range) = self.section(name) {
+            self.lines.drain(range.start - 1..range.end);
+        }
+    }
+
+    /// Add a section at the end of the file, separated by an empty line
+    fn push_section(&mut self, name: &str, lines: Vec<String>) {
+        if self.lines.last().is_some_and(|x| !x.trim().is_empty()) {
+            self.lines.push(String::new());
+        }
+        self.lines.push(format!("[{name}]"));
+        self.lines.extend(lines);
+    }
+}
+
+impl fmt::Display for NetworkdFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let len = self
+            .lines
+            .iter()
+            .rposition(|x| !x.trim().is_empty())
+            .map_or(0, |x| x + 1);
+        for line in &self.lines[..len] {
+            writeln!(f, "{line}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Read a numeric interface option from an existing netdev file
+///
+/// networkd accepts more formats than plain numbers (e.g. `MTUBytes=1.5K` or `FirewallMark=0x10`).
+/// We don't know these, so such values are treated as not set and left as they are on disk.
+fn link_option(ini: &Ini, section: &str, key: &str) -> Option<u32> {
+    let value = ini.get_from(Some(section), key)?;
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            tracing::warn!("Leaving {key}={value} in [{section}] as it is as we can't parse it");
+            None
+        }
+    }
+}
 
-/// Find a free address in a network given a list of occupied addresses.
+/// Properties of the WireGuard interface that can be configured by the user
 ///
-/// Returns `None` if there are no free addresses.
-#[tracing::instrument]
-fn get_free_address(network: &IpNet, peers: &HashSet<WgPeer>) -> Option<IpAddr> {
-    let occupied_addresses = peers
-        .iter()
-        .map(|x| x.address.addr())
-        .collect::<HashSet<_>>();
-    for host in network.hosts() {
-        if !occupied_addresses.contains(&host) {
-            return Some(host);
-        }
-    }
-    None
+/// Any option that is `None` is left as it is in the config on disk.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct LinkOptions {
+    /// MTU of the WireGuard interface (`MTUBytes=`)
+    pub mtu: Option<u32>,
+
+    /// Firewall mark set on outgoing WireGuard packets (`FirewallMark=`)
+    pub fwmark: Option<u32>,
+
+    /// Routing table the routes for the peers' `AllowedIPs` are added to (`RouteTable=`)
+    pub route_table: Option<String>,
+
+    /// Metric of the routes for the peers' `AllowedIPs` (`RouteMetric=`)
+    pub route_metric: Option<u32>,
+}
+
+impl LinkOptions {
+    /// Take over all options that are set in `other`
+    ///
+    /// Returns whether any option changed.
+    pub fn update_from(&mut self, other: &LinkOptions) -> bool {
+        let old = self.clone();
+        if other.mtu.is_some() {
+            self.mtu = other.mtu;
+        }
+        if other.fwmark.is_some() {
+            self.fwmark = other.fwmark;
+        }
+        if other.route_table.is_some() {
+            self.route_table.clone_from(&other.route_table);
+        }
+        if other.route_metric.is_some() {
+            self.route_metric = other.route_metric;
+        }
+        *self != old
+    }
 }
 
 pub struct NetworkdConfiguration {
     pub wg_address: IpNet,
     pub wg_interface: String,
     pub wg_port: u16,
+    pub link_options: LinkOptions,
     pub peers: HashSet<WgPeer>,
     pub private_key: Privkey,
     pub public_key: Pubkey,
@@ -43,6 +213,7 @@ impl fmt::Debug for NetworkdConfiguration {
             .field("wg_address", &self.wg_address)
             .field("wg_interface", &self.wg_interface)
             .field("wg_port", &self.wg_port)
+            .field("link_options", &self.link_options)
             .field("peers", &self.peers)
             .field("private_key", &"[REDACTED]")
             .field("public_key", &self.public_key.to_base64_urlsafe())
@@ -52,38 +223,69 @@ impl fmt::Debug for NetworkdConfiguration {
 
 impl NetworkdConfiguration {
     /// Build a new config
-    #[tracing::instrument]
+    ///
+    /// The address needs to be picked beforehand, see [`crate::allocation::AddressAllocator`].
+    #[tracing::instrument(skip(private_key))]
     pub fn new(
-        address: Option<IpAddr>,
+        private_key: Privkey,
+        address: IpAddr,
         network: IpNet,
         port: u16,
         wg_interface: &str,
+        link_options: LinkOptions,
         peers: HashSet<WgPeer>,
     ) -> Result<Self> {
-        let address = if let Some(address) = address {
-            address
-        } else {
-            get_free_address(&network, &peers).context("Couldn't find usable address")?
-        };
-
         let wg_address = IpNet::new(address, network.prefix_len())?;
-        let private_key = wireguard_keys::Privkey::generate();
         Ok(Self {
             wg_address,
             wg_interface: wg_interface.to_string(),
             wg_port: port,
+            link_options,
             peers,
             private_key,
             public_key: private_key.pubkey(),
         })
     }
 
+    /// Move the config to the given address, network and port
+    ///
+    /// Returns whether anything changed.
+    pub fn migrate(&mut self, address: IpAddr, network: IpNet, port: u16) -> Result<bool> {
+        let wg_address = IpNet::new(address, network.prefix_len())?;
+        let changed = wg_address != self.wg_address || port != self.wg_port;
+        self.wg_address = wg_address;
+        self.wg_port = port;
+        Ok(changed)
+    }
+
+    /// Read the config from disk and make sure it still uses our address and port
+    ///
+    /// The files might have been changed since we started, e.g. by config management that still
+    /// uses an old network or port. We published `wg_address` and `wg_port`, so rather than
+    /// running with a config that doesn't match, we move it back to them.
+    pub async fn reconcile(
+        networkd_dir: &Path,
+        wg_interface: &str,
+        wg_address: IpNet,
+        wg_port: u16,
+    ) -> Result<Self> {
+        let mut config = Self::from_config(networkd_dir, wg_interface).await?;
+        let (current_address, current_port) = (config.wg_address, config.wg_port);
+        if config.migrate(wg_address.addr(), wg_address.trunc(), wg_port)? {
+            tracing::warn!(
+                "Config has address {current_address} and port {current_port} instead of \
+                 {wg_address} and {wg_port}, restoring them"
+            );
+        }
+        Ok(config)
+    }
+
     /// Read and parse existing config from existing location on disk
     #[tracing::instrument]
     pub async fn from_config(networkd_dir: &Path, wg_interface: &str) -> Result<Self> {
         // Get the list of peers in networkd.
         let netdev_path = networkd_dir.join(wg_interface).with_extension("netdev");
-        let netdev_ini = ini::Ini::load_from_file(netdev_path)?;
+        let netdev_ini = Ini::load_from_file_noescape(netdev_path)?;
 
         let wg_port = netdev_ini
             .section(Some("WireGuard"))
@@ -99,26 +301,59 @@ impl NetworkdConfiguration {
             .parse()?;
         let public_key = private_key.pubkey();
 
+        let link_options = LinkOptions {
+            mtu: link_option(&netdev_ini, "NetDev", "MTUBytes"),
+            fwmark: link_option(&netdev_ini, "WireGuard", "FirewallMark"),
+            route_table: netdev_ini
+                .get_from(Some("WireGuard"), "RouteTable")
+                .map(str::to_string),
+            route_metric: link_option(&netdev_ini, "WireGuard", "RouteMetric"),
+        };
+
         let mut peers = HashSet::new();
         for peer in netdev_ini.section_all(Some("WireGuardPeer")) {
             let public_key = peer
                 .get("PublicKey")
                 .context("No PublicKey attribute on WireGuardPeer")?;
-            let endpoint = peer
-                .get("Endpoint")
-                .context("No Endpoint attribute on WireGuardPeer")?;
-            let allowed_ips = peer
+            // Roaming peers don't have an endpoint.
+            let endpoint = peer.get("Endpoint").unwrap_or_default();
+            // Relayed peers don't have any AllowedIPs as their address is listed on the relay
+            // instead. We don't know the address in that case.
+            let mut allowed_ips = peer
                 .get("AllowedIPs")
-                .context("No AllowedIPs attribute on WireGuardPeer")?;
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|x| !x.is_empty())
+                .map(str::parse)
+                .collect::<Result<Vec<IpNet>, _>>()?;
+            let relayed = allowed_ips.is_empty();
+            let address = if relayed {
+                IpNet::default()
+            } else {
+                allowed_ips.remove(0)
+            };
             peers.insert(WgPeer {
                 public_key: Pubkey::from_base64(public_key)?,
                 endpoint: endpoint.parse()?,
-                address: allowed_ips.parse()?,
+                endpoint_candidates: vec![],
+                datacenter_endpoint: None,
+                datacenter: None,
+                address,
+                mtu: None,
+                keepalive: None,
+                role: None,
+                tags: Tags::new(),
+                relay: false,
+                hub: None,
+                relayed,
+                relayed_addresses: allowed_ips,
+                metadata: NodeMetadata::default(),
             });
         }
 
         let network_path = networkd_dir.join(wg_interface).with_extension("network");
-        let network_ini = ini::Ini::load_from_file(network_path)?;
+        let network_ini = Ini::load_from_file_noescape(network_path)?;
 
         let wg_address = network_ini
             .section(Some("Network"))
@@ -131,6 +366,7 @@ impl NetworkdConfiguration {
             wg_interface: wg_interface.to_string(),
             wg_address,
             wg_port,
+            link_options,
             peers,
             private_key,
             public_key,
@@ -138,44 +374,20 @@ impl NetworkdConfiguration {
     }
 
     /// Generate and write systemd-networkd config
+    ///
+    /// If the files already exist, our changes are merged into them so that sections and keys we
+    /// don't manage (e.g. routes, comments or an additional `Address=` added by an admin) are
+    /// preserved. We only own the `[WireGuardPeer]` sections and the first occurrence of the
+    /// handful of keys set below.
+    ///
+    /// The files are only written if their contents actually changed. Returns whether that was
+    /// the case, meaning that systemd-networkd needs to be restarted to apply the new config.
     #[tracing::instrument]
-    pub async fn write_config(&self, networkd_dir: &Path, persistent_keepalive: u64) -> Result<()> {
-        let network_file = format!(
-            "\
-[Match]
-Name={}
-
-[Network]
-Address={}\n",
-            self.wg_interface, self.wg_address
-        );
-
-        let mut netdev_file = format!(
-            "\
-[NetDev]
-Name={}
-Kind=wireguard
-Description=WireGuard client
-MTUBytes=1280
-
-[WireGuard]
-ListenPort={}
-PrivateKey={}\n",
-            self.wg_interface, self.wg_port, self.private_key
-        );
-
-        for peer in &self.peers {
-            let peer_str = format!(
-                "\n
-[WireGuardPeer]
-PublicKey={}
-Endpoint={}
-AllowedIPs={}
-PersistentKeepalive={}",
-                peer.public_key, peer.endpoint, peer.address, persistent_keepalive
-            );
-            netdev_file.push_str(&peer_str);
-        }
+    pub async fn write_config(
+        &self,
+        networkd_dir: &Path,
+        keepalive_policy: KeepalivePolicy,
+    ) -> Result<bool> {
         let network_path = networkd_dir
             .join(&self.wg_interface)
             .with_extension("network");
@@ -183,6 +395,76 @@ PersistentKeepalive={}",
             .join(&self.wg_interface)
             .with_extension("netdev");
 
+        let existing_network_file = read_existing_file(&network_path).await?;
+        let mut network = NetworkdFile::new(existing_network_file.as_deref());
+        network.set("Match", "Name", &self.wg_interface);
+        network.set("Network", "Address", &self.wg_address.to_string());
+
+        let existing_netdev_file = read_existing_file(&netdev_path).await?;
+        let mut netdev = NetworkdFile::new(existing_netdev_file.as_deref());
+        netdev.set("NetDev", "Name", &self.wg_interface);
+        netdev.set("NetDev", "Kind", "wireguard");
+
+        // These are merely defaults, an admin is free to override them.
+        netdev.set_default("NetDev", "Description", "WireGuard client");
+        if let Some(mtu) = self.link_options.mtu {
+            netdev.set("NetDev", "MTUBytes", &mtu.to_string());
+        } else {
+            netdev.set_default("NetDev", "MTUBytes", "1280");
+        }
+
+        netdev.set("WireGuard", "ListenPort", &self.wg_port.to_string());
+        netdev.set("WireGuard", "PrivateKey", &self.private_key.to_base64());
+        if let Some(fwmark) = self.link_options.fwmark {
+            netdev.set("WireGuard", "FirewallMark", &fwmark.to_string());
+        }
+        if let Some(route_table) = &self.link_options.route_table {
+            netdev.set("WireGuard", "RouteTable", route_table);
+        }
+        if let Some(route_metric) = self.link_options.route_metric {
+            netdev.set("WireGuard", "RouteMetric", &route_metric.to_string());
+        }
+
+        // Peers are entirely managed by us so we always regenerate them. Sort them to get a stable
+        // output.
+        netdev.remove_sections("WireGuardPeer");
+        let mut peers = self.peers.iter().collect::<Vec<_>>();
+        peers.sort_by_key(|peer| peer.public_key.to_base64());
+        for peer in peers {
+            let mut lines = Vec::new();
+            // `[WireGuardPeer]` doesn't support a `Description=` so we put the peer metadata in a
+            // comment instead.
+            if let Some(description) = peer.metadata.description() {
+                lines.push(format!("# {description}"));
+            }
+            lines.push(format!("PublicKey={}", peer.public_key.to_base64()));
+            if !peer.endpoint.is_empty() {
+                lines.push(format!("Endpoint={}", peer.endpoint));
+            }
+            let allowed_ips = (!peer.relayed)
+                .then_some(peer.address)
+                .into_iter()
+                .chain(peer.relayed_addresses.iter().copied())
+                .map(|x| x.to_string())
+                .collect::<Vec<_>>();
+            if !allowed_ips.is_empty() {
+                lines.push(format!("AllowedIPs={}", allowed_ips.join(", ")));
+            }
+            lines.push(format!(
+                "PersistentKeepalive={}",
+                keepalive_policy.for_peer(peer)
+            ));
+            netdev.push_section("WireGuardPeer", lines);
+        }
+
+        let network_file = network.to_string();
+        let netdev_file = netdev.to_string();
+        if existing_network_file.as_ref() == Some(&network_file)
+            && existing_netdev_file.as_ref() == Some(&netdev_file)
+        {
+            return Ok(false);
+        }
+
         fs::write(&network_path, network_file)
             .await
             .context(format!("Couldn't write config to {network_path:?}"))?;
@@ -192,7 +474,7 @@ PersistentKeepalive={}",
         fs::set_permissions(&netdev_path, Permissions::from_mode(0o640)).await?;
         set_group(netdev_path, "systemd-network")?;
 
-        Ok(())
+        Ok(true)
     }
 
     /// Restart systemd-networkd
@@ -0,0 +1,182 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use futures::future::join_all;
+use tokio::{net::lookup_host, process::Command};
+use tracing::{debug, info, trace};
+use wireguard_keys::Pubkey;
+
+use crate::wireguard::WgPeer;
+
+/// Smallest MTU we'll ever configure as it's the minimum MTU required by IPv6.
+pub const MIN_MTU: u32 = 1280;
+
+/// Upper bound for path MTU discovery if no `--mtu` was provided.
+///
+/// This is the WireGuard default which fits into a 1500 byte underlay for both IPv4 and IPv6.
+pub const DEFAULT_MAX_MTU: u32 = 1420;
+
+/// Difference between the size of an ICMP echo payload and the WireGuard MTU for the same packet
+/// size on the underlay.
+///
+/// The outer IP header is the same in both cases. An ICMP echo adds 8 bytes of header whereas
+/// WireGuard adds 8 bytes of UDP header, 16 bytes of data message header and a 16 byte auth tag.
+const ICMP_TO_WIREGUARD_OVERHEAD: u32 = 32;
+
+/// Send a single ping with the Don't Fragment flag set
+///
+/// Returns whether we got a reply.
+async fn ping_without_fragmentation(address: IpAddr, payload_size: u32) -> Result<bool> {
+    let output = Command::new("ping")
+        .arg(if address.is_ipv4() { "-4" } else { "-6" })
+        .args(["-M", "do"])
+        .args(["-c", "1"])
+        .args(["-W", "1"])
+        .args(["-s", &payload_size.to_string()])
+        .arg(address.to_string())
+        .output()
+        .await
+        .context("Failed to run ping")?;
+    Ok(output.status.success())
+}
+
+/// Find the largest WireGuard MTU that fits through the path to an endpoint
+///
+/// This performs a binary search between [`MIN_MTU`] and `max_mtu` using pings that must not be
+/// fragmented.
+#[tracing::instrument]
+pub async fn probe_path_mtu(endpoint: &str, max_mtu: u32) -> Result<u32> {
+    let address = lookup_host(endpoint)
+        .await
+        .context(format!("Couldn't resolve {endpoint}"))?
+        .next()
+        .context(format!("{endpoint} didn't resolve to any address"))?
+        .ip();
+
+    if !ping_without_fragmentation(address, MIN_MTU + ICMP_TO_WIREGUARD_OVERHEAD).await? {
+        bail!("{endpoint} isn't reachable with an MTU of {MIN_MTU}");
+    }
+
+    // `low` is always known to work.
+    let mut low = MIN_MTU;
+    let mut high = max_mtu.max(MIN_MTU);
+    while low < high {
+        let mid = low + (high - low).div_ceil(2);
+        if ping_without_fragmentation(address, mid + ICMP_TO_WIREGUARD_OVERHEAD).await? {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+
+    trace!("Path MTU to {endpoint} allows for a WireGuard MTU of {low}");
+    Ok(low)
+}
+
+struct ProbeResult {
+    /// Name of the peer for logging
+    name: String,
+    endpoint: String,
+    /// `None` if the peer couldn't be probed and didn't publish an MTU either
+    mtu: Option<u32>,
+    probed_at: Instant,
+}
+
+/// Keeps track of the path MTU to each peer
+///
+/// Peers are only probed again once their last result is older than the probe period or their
+/// endpoint changed.
+pub struct PathMtuDiscovery {
+    max_mtu: u32,
+    probe_period: Duration,
+    results: HashMap<Pubkey, ProbeResult>,
+    limiting_peer: Option<Pubkey>,
+}
+
+impl PathMtuDiscovery {
+    pub fn new(max_mtu: u32, probe_period: Duration) -> Self {
+        Self {
+            max_mtu,
+            probe_period,
+            results: HashMap::new(),
+            limiting_peer: None,
+        }
+    }
+
+    /// Get the largest MTU that is safe to use with all of the given peers
+    ///
+    /// Peers which can't be probed, e.g. because ICMP is filtered, fall back to the MTU they
+    /// published themselves. If they didn't publish one either, we don't know anything about the
+    /// path to them so they don't lower the MTU but keep it from going above `current_mtu`. Peers
+    /// without an endpoint are skipped as there's nothing to probe.
+    #[tracing::instrument(skip_all)]
+    pub async fn safe_mtu(&mut self, peers: &HashSet<WgPeer>, current_mtu: u32) -> u32 {
+        let peers = peers
+            .iter()
+            .filter(|peer| !peer.endpoint.is_empty())
+            .collect::<Vec<_>>();
+        self.results
+            .retain(|public_key, _| peers.iter().any(|x| x.public_key == *public_key));
+
+        let outdated_peers = peers
+            .into_iter()
+            .filter(|peer| match self.results.get(&peer.public_key) {
+                Some(result) => {
+                    result.endpoint != peer.endpoint
+                        || result.probed_at.elapsed() >= self.probe_period
+                }
+                None => true,
+            })
+            .collect::<Vec<_>>();
+        let probes = join_all(
+            outdated_peers
+                .iter()
+                .map(|peer| probe_path_mtu(&peer.endpoint, self.max_mtu)),
+        )
+        .await;
+        for (peer, probe) in outdated_peers.into_iter().zip(probes) {
+            let mtu = match probe {
+                Ok(mtu) => Some(mtu),
+                Err(err) => {
+                    debug!("Couldn't probe path MTU to {}: {err:?}", peer.name());
+                    peer.mtu
+                }
+            };
+            self.results.insert(
+                peer.public_key,
+                ProbeResult {
+                    name: peer.name(),
+                    endpoint: peer.endpoint.clone(),
+                    mtu,
+                    probed_at: Instant::now(),
+                },
+            );
+        }
+
+        let max_mtu = if self.results.values().any(|x| x.mtu.is_none()) {
+            current_mtu.min(self.max_mtu)
+        } else {
+            self.max_mtu
+        };
+        let limit = self
+            .results
+            .iter()
+            .filter_map(|(public_key, result)| Some((public_key, result, result.mtu?)))
+            .filter(|(_, _, mtu)| *mtu < max_mtu)
+            .min_by_key(|(_, _, mtu)| *mtu);
+        let Some((public_key, result, mtu)) = limit else {
+            self.limiting_peer = None;
+            return max_mtu.max(MIN_MTU);
+        };
+
+        if self.limiting_peer != Some(*public_key) {
+            info!("Peer {} forces the MTU down to {mtu}", result.name);
+            self.limiting_peer = Some(*public_key);
+        }
+        mtu.max(MIN_MTU)
+    }
+}
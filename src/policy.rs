@@ -0,0 +1,78 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::wireguard::WgPeer;
+
+/// Tags published by a node, e.g. `env=prod`
+pub type Tags = BTreeMap<String, String>;
+
+/// Matches nodes which have all of the given tags
+///
+/// An empty selector matches every node.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct TagSelector(pub Tags);
+
+impl TagSelector {
+    pub fn matches(&self, tags: &Tags) -> bool {
+        self.0
+            .iter()
+            .all(|(key, value)| tags.get(key) == Some(value))
+    }
+}
+
+/// Allows nodes matching one selector to peer with nodes matching the other one
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct AllowRule {
+    pub between: [TagSelector; 2],
+}
+
+impl AllowRule {
+    /// Rules are symmetric so that both sides of a tunnel come to the same conclusion.
+    fn allows(&self, a: &Tags, b: &Tags) -> bool {
+        let [x, y] = &self.between;
+        (x.matches(a) && y.matches(b)) || (x.matches(b) && y.matches(a))
+    }
+}
+
+/// Decides which nodes may peer with each other
+///
+/// This is read from JSON like this:
+///
+/// ```json
+/// {"allow": [{"between": [{"env": "prod"}, {"env": "prod"}]}]}
+/// ```
+///
+/// Two nodes may peer if any rule allows it.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PeeringPolicy {
+    #[serde(default)]
+    pub allow: Vec<AllowRule>,
+}
+
+impl PeeringPolicy {
+    /// Read a policy from a JSON file
+    pub async fn from_file(path: &Path) -> Result<Self> {
+        let content = tokio::fs::read(path)
+            .await
+            .context(format!("Couldn't read policy file {path:?}"))?;
+        serde_json::from_slice(&content).context(format!("Couldn't parse policy file {path:?}"))
+    }
+
+    pub fn allows(&self, a: &Tags, b: &Tags) -> bool {
+        self.allow.iter().any(|rule| rule.allows(a, b))
+    }
+
+    /// Get the peers a node with the given tags may peer with
+    pub fn filter(&self, own_tags: &Tags, peers: HashSet<WgPeer>) -> HashSet<WgPeer> {
+        peers
+            .into_iter()
+            .filter(|x| self.allows(own_tags, &x.tags))
+            .collect()
+    }
+}
@@ -0,0 +1,149 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, SystemTime},
+};
+
+use tracing::info;
+use wireguard_keys::Pubkey;
+
+use crate::wireguard::{KeepalivePolicy, WgPeer};
+
+/// How we currently reach a peer
+enum Route {
+    /// Directly, since the given time
+    Direct(SystemTime),
+
+    /// Via the given relay, since the given time
+    Relayed(Pubkey, SystemTime),
+}
+
+/// Routes peers we can't reach directly via a relay
+///
+/// If we don't get a handshake with a peer within the timeout, its address is moved to the
+/// `AllowedIPs` of a relay. The peer itself stays configured without any `AllowedIPs` so that we
+/// keep trying to reach it directly. Once we get a direct handshake again, we switch back.
+///
+/// Peers we don't have a keepalive with are never relayed as we can't tell whether they're
+/// reachable without traffic. Neither are roaming peers without an endpoint as they connect to
+/// us whenever they're online.
+pub struct RelayFallback {
+    timeout: Duration,
+    routes: HashMap<Pubkey, Route>,
+}
+
+impl RelayFallback {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            routes: HashMap::new(),
+        }
+    }
+
+    /// Mark the peers that need to be relayed and add their addresses to their relays
+    ///
+    /// `latest_handshakes` is `None` if we couldn't find out about handshakes, in which case we
+    /// stick to the routes we already have.
+    pub fn apply(
+        &mut self,
+        peers: HashSet<WgPeer>,
+        keepalive_policy: KeepalivePolicy,
+        latest_handshakes: Option<&HashMap<Pubkey, SystemTime>>,
+    ) -> HashSet<WgPeer> {
+        self.routes
+            .retain(|public_key, _| peers.iter().any(|x| x.public_key == *public_key));
+
+        let now = SystemTime::now();
+        let handshake_since = |public_key: &Pubkey, since: SystemTime| {
+            latest_handshakes
+                .and_then(|x| x.get(public_key))
+                .is_some_and(|x| *x >= since)
+        };
+        let cutoff = now
+            .checked_sub(self.timeout)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+
+        // Relays need to be reachable directly themselves. Prefer the one with the lowest address
+        // so that both sides of a broken tunnel likely pick the same relay.
+        let mut relays = peers
+            .iter()
+            .filter(|x| x.relay && handshake_since(&x.public_key, cutoff))
+            .collect::<Vec<_>>();
+        relays.sort_by_key(|x| x.address);
+
+        for peer in &peers {
+            let route = self
+                .routes
+                .entry(peer.public_key)
+                .or_insert(Route::Direct(now));
+            let Some(latest_handshakes) = latest_handshakes else {
+                continue;
+            };
+            let latest_handshake = latest_handshakes.get(&peer.public_key);
+
+            match route {
+                Route::Direct(since) => {
+                    let since = latest_handshake.map_or(*since, |x| (*since).max(*x));
+                    let timed_out = now.duration_since(since).is_ok_and(|x| x >= self.timeout);
+                    if !timed_out
+                        || keepalive_policy.for_peer(peer) == 0
+                        || peer.endpoint.is_empty()
+                    {
+                        continue;
+                    }
+                    let Some(relay) = relays.iter().find(|x| x.public_key != peer.public_key)
+                    else {
+                        continue;
+                    };
+                    info!(
+                        "No handshake with peer {}, relaying via {}",
+                        peer.public_key.to_base64_urlsafe(),
+                        relay.public_key.to_base64_urlsafe()
+                    );
+                    *route = Route::Relayed(relay.public_key, now);
+                }
+                Route::Relayed(relay, since) => {
+                    let relay_gone = !relays.iter().any(|x| x.public_key == *relay);
+                    if latest_handshake.is_some_and(|x| *x >= *since) || relay_gone {
+                        info!(
+                            "Reaching peer {} directly again",
+                            peer.public_key.to_base64_urlsafe()
+                        );
+                        *route = Route::Direct(now);
+                    }
+                }
+            }
+        }
+
+        let relayed = self
+            .routes
+            .iter()
+            .filter_map(|(public_key, route)| match route {
+                Route::Relayed(relay, _) => Some((*public_key, *relay)),
+                Route::Direct(_) => None,
+            })
+            .collect::<HashMap<_, _>>();
+        let relayed_addresses = peers
+            .iter()
+            .filter_map(|x| Some((*relayed.get(&x.public_key)?, x.address)))
+            .fold(HashMap::<_, Vec<_>>::new(), |mut acc, (relay, address)| {
+                acc.entry(relay).or_default().push(address);
+                acc
+            });
+
+        peers
+            .into_iter()
+            .map(|x| {
+                let mut relayed_addresses = relayed_addresses
+                    .get(&x.public_key)
+                    .cloned()
+                    .unwrap_or_default();
+                relayed_addresses.sort();
+                WgPeer {
+                    relayed: relayed.contains_key(&x.public_key),
+                    relayed_addresses,
+                    ..x
+                }
+            })
+            .collect()
+    }
+}
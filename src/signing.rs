@@ -0,0 +1,242 @@
+use std::{fmt, net::IpAddr, path::Path};
+
+use anyhow::{bail, ensure, Context, Result};
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use wireguard_keys::Pubkey;
+
+/// Key of the field holding the signature in a signed JSON object
+const SIGNATURE_FIELD: &str = "signature";
+
+/// # Enrolment of a node signed by the mesh authority
+///
+/// The enrolment binds the public key of a node to its address. It's signed offline with the
+/// secret key of the authority and published as part of the peer record of the node, so a node
+/// can't publish records for any other key or address.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Enrolment {
+    pub public_key: Pubkey,
+    pub address: IpAddr,
+
+    /// Base64 encoded Ed25519 signature over the other fields.
+    pub signature: String,
+}
+
+impl Enrolment {
+    pub async fn from_file(path: &Path) -> Result<Self> {
+        let content = tokio::fs::read(path)
+            .await
+            .context(format!("Couldn't read enrolment {path:?}"))?;
+        serde_json::from_slice(&content).context(format!("Couldn't parse enrolment {path:?}"))
+    }
+
+    /// Whether the enrolment is for the given node
+    pub fn matches(&self, public_key: Pubkey, address: IpAddr) -> bool {
+        self.public_key == public_key && self.address == address
+    }
+}
+
+/// # Secret key of the mesh authority
+///
+/// This is only needed to sign enrolments and should be kept offline rather than be given to the
+/// nodes. The key file contains the base64 encoded 32 byte secret key which can be generated with
+/// e.g. `head -c 32 /dev/urandom | base64`.
+#[derive(Clone)]
+pub struct AuthorityKey {
+    signing_key: SigningKey,
+}
+
+impl AuthorityKey {
+    pub fn from_base64(key: &str) -> Result<Self> {
+        Ok(Self {
+            signing_key: SigningKey::from_bytes(&decode_key(key)?),
+        })
+    }
+
+    pub async fn from_file(path: &Path) -> Result<Self> {
+        let key = tokio::fs::read_to_string(path)
+            .await
+            .context(format!("Couldn't read authority secret key {path:?}"))?;
+        Self::from_base64(&key)
+    }
+
+    /// The public key nodes verify enrolments with
+    pub fn authority(&self) -> MeshAuthority {
+        MeshAuthority {
+            verifying_key: self.signing_key.verifying_key(),
+        }
+    }
+
+    /// Sign the enrolment of a node
+    pub fn enrol(&self, public_key: Pubkey, address: IpAddr) -> Result<Enrolment> {
+        let mut value = serde_json::json!({
+            "public_key": public_key,
+            "address": address,
+        });
+        self.sign(&mut value)?;
+        Ok(serde_json::from_value(value)?)
+    }
+
+    /// Add a signature to a JSON object
+    pub fn sign(&self, value: &mut Value) -> Result<()> {
+        sign_value(&self.signing_key, value)
+    }
+}
+
+/// Never show the secret key so that it doesn't end up in logs.
+impl fmt::Debug for AuthorityKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthorityKey")
+            .field("authority", &self.authority())
+            .finish()
+    }
+}
+
+/// # Public key of the mesh authority
+///
+/// Only peer records carrying an enrolment signed by the authority are accepted, so that write
+/// access to the Consul prefix alone isn't enough to join the mesh. Nodes only need this public
+/// key, so a compromised node can't enrol anyone else.
+///
+/// The key file contains the base64 encoded 32 byte public key as printed by
+/// `wiresmith authority-public-key`.
+#[derive(Clone, Eq, PartialEq)]
+pub struct MeshAuthority {
+    verifying_key: VerifyingKey,
+}
+
+impl MeshAuthority {
+    pub fn from_base64(key: &str) -> Result<Self> {
+        Ok(Self {
+            verifying_key: VerifyingKey::from_bytes(&decode_key(key)?)
+                .context("Authority public key isn't a valid Ed25519 key")?,
+        })
+    }
+
+    pub async fn from_file(path: &Path) -> Result<Self> {
+        let key = tokio::fs::read_to_string(path)
+            .await
+            .context(format!("Couldn't read authority public key {path:?}"))?;
+        Self::from_base64(&key)
+    }
+
+    pub fn to_base64(&self) -> String {
+        BASE64_STANDARD.encode(self.verifying_key.as_bytes())
+    }
+
+    /// Verify that an enrolment was signed by the authority and is for the given node
+    pub fn verify_enrolment(
+        &self,
+        enrolment: &Enrolment,
+        public_key: Pubkey,
+        address: IpAddr,
+    ) -> Result<()> {
+        self.verify(&serde_json::to_value(enrolment)?)?;
+        ensure!(
+            enrolment.matches(public_key, address),
+            "Enrolment is for {} with address {} instead",
+            enrolment.public_key.to_base64_urlsafe(),
+            enrolment.address
+        );
+        Ok(())
+    }
+
+    /// Verify the signature of a JSON object
+    ///
+    /// The signature covers all fields, including the ones we don't know about, so values
+    /// written by newer versions can still be verified.
+    pub fn verify(&self, value: &Value) -> Result<()> {
+        verify_value(&self.verifying_key, value)
+    }
+}
+
+impl fmt::Debug for MeshAuthority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MeshAuthority")
+            .field("verifying_key", &self.to_base64())
+            .finish()
+    }
+}
+
+/// # Key derived from the secret of a join token
+///
+/// Nodes sign their claim of a join token with it. The token only holds the public half, so
+/// claims can be verified by anyone while only nodes knowing the secret can make them.
+pub struct ClaimKey {
+    signing_key: SigningKey,
+}
+
+impl ClaimKey {
+    pub fn from_secret(secret: &str) -> Self {
+        // The token ID is the plain hash of the secret, so the key needs to be derived differently.
+        let mut hasher = Sha256::new();
+        hasher.update(b"wiresmith join token claim key\0");
+        hasher.update(secret.trim().as_bytes());
+        Self {
+            signing_key: SigningKey::from_bytes(&hasher.finalize().into()),
+        }
+    }
+
+    /// The base64 encoded public half of the key
+    pub fn public_key(&self) -> String {
+        BASE64_STANDARD.encode(self.signing_key.verifying_key().as_bytes())
+    }
+
+    /// Add a signature to a JSON object
+    pub fn sign(&self, value: &mut Value) -> Result<()> {
+        sign_value(&self.signing_key, value)
+    }
+
+    /// Verify the signature of a JSON object against the base64 encoded public half of a key
+    pub fn verify(public_key: &str, value: &Value) -> Result<()> {
+        let verifying_key = VerifyingKey::from_bytes(&decode_key(public_key)?)
+            .context("Claim key isn't a valid Ed25519 key")?;
+        verify_value(&verifying_key, value)
+    }
+}
+
+fn sign_value(signing_key: &SigningKey, value: &mut Value) -> Result<()> {
+    let object = value
+        .as_object_mut()
+        .context("Only JSON objects can be signed")?;
+    object.remove(SIGNATURE_FIELD);
+    let signature = signing_key.sign(&canonical_bytes(value)?);
+    value[SIGNATURE_FIELD] = BASE64_STANDARD.encode(signature.to_bytes()).into();
+    Ok(())
+}
+
+fn verify_value(verifying_key: &VerifyingKey, value: &Value) -> Result<()> {
+    let mut value = value.clone();
+    let object = value.as_object_mut().context("Expected a JSON object")?;
+    let Some(signature) = object.remove(SIGNATURE_FIELD) else {
+        bail!("Value isn't signed");
+    };
+    let signature = signature.as_str().context("Signature isn't a string")?;
+    let signature = BASE64_STANDARD
+        .decode(signature)
+        .context("Signature isn't valid base64")?;
+    let signature = Signature::from_slice(&signature)?;
+    verifying_key
+        .verify(&canonical_bytes(&value)?, &signature)
+        .context("Invalid signature")
+}
+
+fn decode_key(key: &str) -> Result<[u8; 32]> {
+    let bytes = BASE64_STANDARD
+        .decode(key.trim())
+        .context("Key isn't valid base64")?;
+    bytes
+        .try_into()
+        .ok()
+        .context("Key needs to be 32 bytes long")
+}
+
+/// Serialize JSON in a canonical form, meaning compact and with sorted keys
+///
+/// `serde_json` sorts the keys of objects as long as its `preserve_order` feature isn't enabled.
+fn canonical_bytes(value: &Value) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec(value)?)
+}
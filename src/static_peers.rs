@@ -0,0 +1,79 @@
+use std::{collections::HashSet, path::Path};
+
+use anyhow::{Context, Result};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+use wireguard_keys::Pubkey;
+
+use crate::wireguard::WgPeer;
+
+/// # A peer that doesn't run wiresmith itself
+///
+/// Static peers are read from the `--static-peers` file, for instance for routers or VPN gateways
+/// of cloud providers. They're configured as they are without going through the peering policy,
+/// the topology or the relay fallback.
+///
+/// The file contains a JSON list like this, other formats such as TOML aren't supported:
+///
+/// ```json
+/// [{"public_key": "...", "endpoint": "198.51.100.1:51820", "allowed_ips": ["10.0.0.200/32"]}]
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct StaticPeer {
+    pub public_key: Pubkey,
+    pub endpoint: String,
+
+    /// Networks routed to the peer, the first one being the address of the peer itself.
+    pub allowed_ips: Vec<IpNet>,
+
+    /// Persistent keepalive in seconds the tunnel to the peer should use.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keepalive: Option<u64>,
+}
+
+impl StaticPeer {
+    pub fn to_wg_peer(&self) -> Result<WgPeer> {
+        let (address, further_ips) = self.allowed_ips.split_first().context(format!(
+            "Static peer {} has no allowed IPs",
+            self.public_key.to_base64_urlsafe()
+        ))?;
+        Ok(WgPeer {
+            address: *address,
+            keepalive: self.keepalive,
+            relayed_addresses: further_ips.to_vec(),
+            ..WgPeer::new(self.public_key, &self.endpoint, address.addr())
+        })
+    }
+}
+
+/// Read the static peers from a JSON file
+pub async fn from_file(path: &Path) -> Result<HashSet<WgPeer>> {
+    let content = tokio::fs::read(path)
+        .await
+        .context(format!("Couldn't read static peers file {path:?}"))?;
+    let static_peers: Vec<StaticPeer> = serde_json::from_slice(&content)
+        .context(format!("Couldn't parse static peers file {path:?} as JSON"))?;
+    static_peers.iter().map(StaticPeer::to_wg_peer).collect()
+}
+
+/// Merge static peers into the discovered ones
+///
+/// Static peers take precedence over discovered peers with the same public key.
+pub fn merge(peers: HashSet<WgPeer>, static_peers: &HashSet<WgPeer>) -> HashSet<WgPeer> {
+    let static_keys = static_peers
+        .iter()
+        .map(|x| x.public_key)
+        .collect::<HashSet<_>>();
+    peers
+        .into_iter()
+        .filter(|x| {
+            let overridden = static_keys.contains(&x.public_key);
+            if overridden {
+                debug!("Peer {} is overridden by a static peer", x.name());
+            }
+            !overridden
+        })
+        .chain(static_peers.iter().cloned())
+        .collect()
+}
@@ -0,0 +1,206 @@
+use std::{
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use tokio::{
+    net::{lookup_host, UdpSocket},
+    time::timeout,
+};
+use tracing::{debug, trace};
+
+use crate::endpoint::with_default_port;
+
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS_RESPONSE: u16 = 0x0101;
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+const ATTRIBUTE_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTRIBUTE_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const HEADER_LENGTH: usize = 20;
+
+/// Default port of STUN servers if none was provided
+const DEFAULT_STUN_PORT: u16 = 3478;
+
+/// How often a request is sent before we give up
+const ATTEMPTS: u32 = 3;
+
+/// Build a STUN Binding Request as described in RFC 5389 section 6
+fn binding_request(transaction_id: &[u8; 12]) -> Vec<u8> {
+    let mut request = Vec::with_capacity(HEADER_LENGTH);
+    request.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    // We don't send any attributes.
+    request.extend_from_slice(&0u16.to_be_bytes());
+    request.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    request.extend_from_slice(transaction_id);
+    request
+}
+
+/// Parse a STUN Binding Success Response and return the mapped address contained in it
+///
+/// XOR-MAPPED-ADDRESS is preferred but we also support MAPPED-ADDRESS for old servers.
+fn parse_binding_response(response: &[u8], transaction_id: &[u8; 12]) -> Result<SocketAddr> {
+    ensure!(
+        response.len() >= HEADER_LENGTH,
+        "STUN response is too short"
+    );
+    let message_type = u16::from_be_bytes([response[0], response[1]]);
+    let message_length = u16::from_be_bytes([response[2], response[3]]) as usize;
+    ensure!(
+        message_type == BINDING_SUCCESS_RESPONSE,
+        "Unexpected STUN message type {message_type:#06x}"
+    );
+    ensure!(
+        response[4..8] == MAGIC_COOKIE.to_be_bytes(),
+        "STUN response doesn't contain the magic cookie"
+    );
+    ensure!(
+        &response[8..20] == transaction_id,
+        "STUN response is for a different transaction"
+    );
+    ensure!(
+        response.len() >= HEADER_LENGTH + message_length,
+        "STUN response is truncated"
+    );
+
+    let mut mapped_address = None;
+    let mut attributes = &response[HEADER_LENGTH..HEADER_LENGTH + message_length];
+    while attributes.len() >= 4 {
+        let attribute_type = u16::from_be_bytes([attributes[0], attributes[1]]);
+        let attribute_length = u16::from_be_bytes([attributes[2], attributes[3]]) as usize;
+        let value = attributes
+            .get(4..4 + attribute_length)
+            .context("STUN attribute is truncated")?;
+
+        match attribute_type {
+            ATTRIBUTE_XOR_MAPPED_ADDRESS => {
+                return parse_address(value, Some(transaction_id));
+            }
+            ATTRIBUTE_MAPPED_ADDRESS => mapped_address = Some(parse_address(value, None)?),
+            _ => {}
+        }
+
+        // Attributes are padded to a multiple of 4 bytes.
+        let padded_length = 4 + attribute_length.div_ceil(4) * 4;
+        attributes = attributes.get(padded_length..).unwrap_or_default();
+    }
+
+    mapped_address.context("STUN response doesn't contain a mapped address")
+}
+
+/// Parse the value of a (XOR-)MAPPED-ADDRESS attribute
+///
+/// If a transaction ID is passed, the address is de-obfuscated as described for
+/// XOR-MAPPED-ADDRESS in RFC 5389 section 15.2.
+fn parse_address(value: &[u8], xor_transaction_id: Option<&[u8; 12]>) -> Result<SocketAddr> {
+    ensure!(value.len() >= 4, "STUN address attribute is too short");
+    let family = value[1];
+    let mut port = u16::from_be_bytes([value[2], value[3]]);
+
+    // The key is the magic cookie followed by the transaction ID.
+    let mut key = [0u8; 16];
+    if let Some(transaction_id) = xor_transaction_id {
+        key[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        key[4..].copy_from_slice(transaction_id);
+        port ^= (MAGIC_COOKIE >> 16) as u16;
+    }
+
+    let ip = match family {
+        0x01 => {
+            let mut octets: [u8; 4] = value
+                .get(4..8)
+                .context("STUN IPv4 address is truncated")?
+                .try_into()?;
+            octets.iter_mut().zip(key).for_each(|(x, k)| *x ^= k);
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        0x02 => {
+            let mut octets: [u8; 16] = value
+                .get(4..20)
+                .context("STUN IPv6 address is truncated")?
+                .try_into()?;
+            octets.iter_mut().zip(key).for_each(|(x, k)| *x ^= k);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        family => bail!("Unknown STUN address family {family:#04x}"),
+    };
+
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Discover the public endpoint of the WireGuard listen port as seen by a STUN server
+///
+/// The request is sent from the listen port so that we learn the port that the NAT maps WireGuard
+/// traffic to. That's only possible while the WireGuard interface isn't up as the kernel holds on
+/// to the port afterwards. Returns `None` if the port is already in use.
+#[tracing::instrument]
+pub async fn discover_endpoint(server: &str, listen_port: u16) -> Result<Option<SocketAddr>> {
+    let server_address = resolve(server).await?;
+    let socket = match UdpSocket::bind((unspecified_address(server_address), listen_port)).await {
+        Ok(socket) => socket,
+        Err(err) if err.kind() == ErrorKind::AddrInUse => {
+            debug!("Listen port {listen_port} is in use, can't discover its mapping via STUN");
+            return Ok(None);
+        }
+        Err(err) => return Err(err).context("Couldn't bind socket for STUN"),
+    };
+    binding(server, server_address, &socket).await.map(Some)
+}
+
+/// Discover our public IP address as seen by a STUN server
+///
+/// The request is sent from a random port, so the port the NAT maps it to doesn't tell anything
+/// about the mapping of the WireGuard listen port.
+#[tracing::instrument]
+pub async fn discover_address(server: &str) -> Result<IpAddr> {
+    let server_address = resolve(server).await?;
+    let socket = UdpSocket::bind((unspecified_address(server_address), 0))
+        .await
+        .context("Couldn't bind socket for STUN")?;
+    Ok(binding(server, server_address, &socket).await?.ip())
+}
+
+async fn resolve(server: &str) -> Result<SocketAddr> {
+    lookup_host(with_default_port(server, DEFAULT_STUN_PORT))
+        .await
+        .context(format!("Couldn't resolve STUN server {server}"))?
+        .next()
+        .context(format!(
+            "STUN server {server} didn't resolve to any address"
+        ))
+}
+
+fn unspecified_address(server_address: SocketAddr) -> IpAddr {
+    if server_address.is_ipv4() {
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+    } else {
+        IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+    }
+}
+
+/// Send a Binding Request from the given socket and return the mapped address
+async fn binding(
+    server: &str,
+    server_address: SocketAddr,
+    socket: &UdpSocket,
+) -> Result<SocketAddr> {
+    let transaction_id = rand::random::<[u8; 12]>();
+    let request = binding_request(&transaction_id);
+    let mut buf = [0u8; 512];
+    for attempt in 1..=ATTEMPTS {
+        socket.send_to(&request, server_address).await?;
+        match timeout(Duration::from_secs(1), socket.recv_from(&mut buf)).await {
+            Ok(Ok((len, from))) if from == server_address => {
+                let endpoint = parse_binding_response(&buf[..len], &transaction_id)?;
+                trace!("STUN server {server} sees us as {endpoint}");
+                return Ok(endpoint);
+            }
+            Ok(Ok((_, from))) => debug!("Ignoring STUN response from unexpected address {from}"),
+            Ok(Err(err)) => return Err(err).context("Couldn't receive STUN response"),
+            Err(_) => debug!("STUN request {attempt}/{ATTEMPTS} to {server} timed out"),
+        }
+    }
+
+    bail!("STUN server {server} didn't respond")
+}
@@ -0,0 +1,99 @@
+use std::collections::{HashMap, HashSet};
+
+use clap::ValueEnum;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use wireguard_keys::Pubkey;
+
+use crate::wireguard::WgPeer;
+
+/// How the nodes of the network peer with each other
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum Topology {
+    /// Every node peers with every other node.
+    #[default]
+    Mesh,
+
+    /// Spokes only peer with hubs while hubs peer with everyone.
+    HubAndSpoke,
+}
+
+/// Role of a node in the [`Topology::HubAndSpoke`] topology
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeRole {
+    Hub,
+    Spoke,
+}
+
+impl Topology {
+    /// Get the peers a node with the given role should configure
+    ///
+    /// In the hub-and-spoke topology, spokes only keep the hubs and route the whole `network` via
+    /// one of them, the one with the lowest address. The other hubs are still reachable directly.
+    /// Traffic between spokes is forwarded by that hub, so it needs to have IP forwarding enabled.
+    ///
+    /// Peers which didn't publish a role, like roaming devices, external peers or nodes running an
+    /// older version, are kept as well, but never used as the gateway.
+    pub fn peers_for(
+        &self,
+        own_role: Option<NodeRole>,
+        network: IpNet,
+        peers: HashSet<WgPeer>,
+    ) -> HashSet<WgPeer> {
+        if *self == Topology::Mesh || own_role != Some(NodeRole::Spoke) {
+            return peers;
+        }
+
+        let peers = peers
+            .into_iter()
+            .filter(|x| x.role != Some(NodeRole::Spoke))
+            .collect::<HashSet<_>>();
+        let gateway = peers
+            .iter()
+            .filter(|x| x.role == Some(NodeRole::Hub))
+            .map(|x| x.address)
+            .min();
+        peers
+            .into_iter()
+            .map(|x| {
+                if x.role == Some(NodeRole::Hub) && Some(x.address) == gateway {
+                    WgPeer {
+                        address: network.trunc(),
+                        ..x
+                    }
+                } else {
+                    x
+                }
+            })
+            .collect()
+    }
+}
+
+/// Route the addresses of peers that are only connected to a hub via that hub
+///
+/// Such peers, e.g. roaming devices exported with `--hub`, only have a tunnel to their hub, so all
+/// other nodes add their address to the `AllowedIPs` of the hub instead of peering with them. If we
+/// don't peer with the hub ourselves, we can't reach the peer at all. The hub keeps the peer as is.
+pub fn route_via_hubs(own_public_key: Pubkey, peers: HashSet<WgPeer>) -> HashSet<WgPeer> {
+    let (behind_hubs, peers): (Vec<_>, Vec<_>) = peers
+        .into_iter()
+        .partition(|x| x.hub.is_some_and(|hub| hub != own_public_key));
+
+    let mut addresses = HashMap::<Pubkey, Vec<IpNet>>::new();
+    for peer in behind_hubs {
+        if let Some(hub) = peer.hub {
+            addresses.entry(hub).or_default().push(peer.address);
+        }
+    }
+    peers
+        .into_iter()
+        .map(|mut x| {
+            if let Some(addresses) = addresses.get(&x.public_key) {
+                x.relayed_addresses.extend(addresses);
+                x.relayed_addresses.sort();
+            }
+            x
+        })
+        .collect()
+}
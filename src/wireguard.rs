@@ -1,19 +1,100 @@
-use std::{fmt, net::IpAddr};
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::{Hash, Hasher},
+    net::IpAddr,
+    time::SystemTime,
+};
 
+use anyhow::{bail, Context, Result};
 use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
+use tokio::process::Command;
 use wireguard_keys::Pubkey;
 
-#[derive(Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+use crate::{
+    endpoint::{EndpointCandidate, DEFAULT_ENDPOINT_PRIORITY},
+    metadata::NodeMetadata,
+    policy::Tags,
+    topology::NodeRole,
+};
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct WgPeer {
     pub public_key: Pubkey,
+
+    /// The endpoint of the peer, empty for roaming devices which connect to us instead.
     pub endpoint: String,
 
+    /// Further endpoints under which the peer might be reachable, e.g. one in the other IP
+    /// family or in a private network.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub endpoint_candidates: Vec<EndpointCandidate>,
+
+    /// Endpoint used exclusively by peers in the same Consul datacenter, usually an address in a
+    /// private network.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub datacenter_endpoint: Option<String>,
+
+    /// The Consul datacenter this peer was read from.
+    ///
+    /// This isn't published but filled in when reading peers from Consul.
+    #[serde(skip)]
+    pub datacenter: Option<String>,
+
     /// The WireGuard internal IP of the peer.
     ///
     /// It should be provided with the most specific netmask as it's meant to for only that peer.
     /// So for IPv4, use /32 and for IPv6, use /128.
     pub address: IpNet,
+
+    /// The WireGuard MTU this peer recommends after performing path MTU discovery.
+    ///
+    /// This is used as a fallback by peers which can't probe the path themselves.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtu: Option<u32>,
+
+    /// The persistent keepalive in seconds this peer wants its tunnels to use.
+    ///
+    /// Peers behind NAT need a keepalive to stay reachable while publicly reachable peers can
+    /// advertise 0 to signal that they don't need one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keepalive: Option<u64>,
+
+    /// The role of the peer in a hub-and-spoke topology.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<NodeRole>,
+
+    /// Tags the peering policy is evaluated against.
+    #[serde(default, skip_serializing_if = "Tags::is_empty")]
+    pub tags: Tags,
+
+    /// Whether the peer forwards traffic between peers that can't reach each other directly.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub relay: bool,
+
+    /// The only node this peer is connected to, e.g. the hub a roaming device was exported for.
+    ///
+    /// All other nodes route the address of this peer via that node.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hub: Option<Pubkey>,
+
+    /// Whether we route the address of this peer via a relay rather than directly.
+    ///
+    /// This isn't published but decided locally.
+    #[serde(skip)]
+    pub relayed: bool,
+
+    /// Further addresses we route via this peer, i.e. those of the peers it relays for us or the
+    /// networks behind a static peer.
+    ///
+    /// This isn't published but decided locally.
+    #[serde(skip)]
+    pub relayed_addresses: Vec<IpNet>,
+
+    /// Informational metadata such as the hostname of the peer.
+    #[serde(default, skip_serializing_if = "NodeMetadata::is_empty")]
+    pub metadata: NodeMetadata,
 }
 
 impl WgPeer {
@@ -21,17 +102,184 @@ impl WgPeer {
         Self {
             public_key,
             endpoint: endpoint.to_string(),
+            endpoint_candidates: vec![],
+            datacenter_endpoint: None,
+            datacenter: None,
             address: address.into(),
+            mtu: None,
+            keepalive: None,
+            role: None,
+            tags: Tags::new(),
+            relay: false,
+            hub: None,
+            relayed: false,
+            relayed_addresses: vec![],
+            metadata: NodeMetadata::default(),
+        }
+    }
+
+    /// All endpoint candidates of the peer, starting with the primary endpoint
+    pub fn candidates(&self) -> Vec<EndpointCandidate> {
+        let primary = EndpointCandidate {
+            endpoint: self.endpoint.clone(),
+            label: None,
+            priority: DEFAULT_ENDPOINT_PRIORITY,
+        };
+        std::iter::once(primary)
+            .chain(self.endpoint_candidates.iter().cloned())
+            .collect()
+    }
+
+    /// Name of the peer for logging, its hostname and public key if available
+    pub fn name(&self) -> String {
+        let public_key = self.public_key.to_base64_urlsafe();
+        match &self.metadata.hostname {
+            Some(hostname) => format!("{hostname} ({public_key})"),
+            None => public_key,
+        }
+    }
+
+    /// The endpoint candidates to use when connecting from the given Consul datacenter
+    ///
+    /// Peers in the same datacenter only use the datacenter endpoint if there is one.
+    pub fn candidates_from(&self, datacenter: Option<&str>) -> Vec<EndpointCandidate> {
+        match &self.datacenter_endpoint {
+            Some(endpoint) if datacenter.is_some() && self.datacenter.as_deref() == datacenter => {
+                vec![EndpointCandidate {
+                    endpoint: endpoint.clone(),
+                    label: Some("datacenter".to_string()),
+                    priority: 0,
+                }]
+            }
+            _ => self.candidates(),
         }
     }
 }
 
+/// The datacenter and relaying aren't part of the published config but only our local view of the
+/// peer and the metadata is purely informational, so they're ignored when comparing peers.
+impl PartialEq for WgPeer {
+    fn eq(&self, other: &Self) -> bool {
+        self.public_key == other.public_key
+            && self.endpoint == other.endpoint
+            && self.endpoint_candidates == other.endpoint_candidates
+            && self.datacenter_endpoint == other.datacenter_endpoint
+            && self.address == other.address
+            && self.mtu == other.mtu
+            && self.keepalive == other.keepalive
+            && self.role == other.role
+            && self.tags == other.tags
+            && self.relay == other.relay
+            && self.hub == other.hub
+    }
+}
+
+impl Eq for WgPeer {}
+
+impl Hash for WgPeer {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.public_key.hash(state);
+        self.endpoint.hash(state);
+        self.endpoint_candidates.hash(state);
+        self.datacenter_endpoint.hash(state);
+        self.address.hash(state);
+        self.mtu.hash(state);
+        self.keepalive.hash(state);
+        self.role.hash(state);
+        self.tags.hash(state);
+        self.relay.hash(state);
+        self.hub.hash(state);
+    }
+}
+
 impl fmt::Debug for WgPeer {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("WgPeer")
             .field("public_key", &self.public_key.to_base64_urlsafe())
             .field("endpoint", &self.endpoint)
+            .field("endpoint_candidates", &self.endpoint_candidates)
+            .field("datacenter_endpoint", &self.datacenter_endpoint)
+            .field("datacenter", &self.datacenter)
             .field("address", &self.address)
+            .field("mtu", &self.mtu)
+            .field("keepalive", &self.keepalive)
+            .field("role", &self.role)
+            .field("tags", &self.tags)
+            .field("relay", &self.relay)
+            .field("hub", &self.hub.map(|x| x.to_base64_urlsafe()))
+            .field("relayed", &self.relayed)
+            .field("relayed_addresses", &self.relayed_addresses)
+            .field("metadata", &self.metadata)
             .finish()
     }
 }
+
+/// Keepalive in seconds for relayed peers which wouldn't get one otherwise
+const RELAYED_PEER_KEEPALIVE: u64 = 25;
+
+/// Decides on the persistent keepalive to use for each peer
+#[derive(Clone, Copy, Debug)]
+pub struct KeepalivePolicy {
+    /// Keepalive in seconds used in place of a keepalive that wasn't advertised
+    pub default: u64,
+
+    /// Keepalive in seconds we advertise ourselves
+    pub own: Option<u64>,
+}
+
+impl KeepalivePolicy {
+    /// Get the keepalive to use for the tunnel to the given peer
+    ///
+    /// If both sides advertised a keepalive, the shorter non-zero one wins so that whichever side
+    /// is behind NAT stays reachable. If neither side advertised one, this falls back to the
+    /// default. Relayed peers always get a keepalive so that we notice when the direct path
+    /// recovers.
+    pub fn for_peer(&self, peer: &WgPeer) -> u64 {
+        let own = self.own.unwrap_or(self.default);
+        let theirs = peer.keepalive.unwrap_or(self.default);
+        let keepalive = match (own, theirs) {
+            (0, keepalive) | (keepalive, 0) => keepalive,
+            (own, theirs) => own.min(theirs),
+        };
+        if peer.relayed && keepalive == 0 {
+            RELAYED_PEER_KEEPALIVE
+        } else {
+            keepalive
+        }
+    }
+}
+
+/// Get the time of the latest handshake with each peer of a WireGuard interface
+///
+/// Peers we never had a handshake with are omitted.
+#[tracing::instrument]
+pub async fn latest_handshakes(wg_interface: &str) -> Result<HashMap<Pubkey, SystemTime>> {
+    let output = Command::new("wg")
+        .arg("show")
+        .arg(wg_interface)
+        .arg("latest-handshakes")
+        .output()
+        .await
+        .context("Failed to run wg")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("Failed to get latest handshakes: {stderr}");
+    }
+
+    // Each line contains the public key of a peer and a UNIX timestamp separated by a tab.
+    let mut handshakes = HashMap::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let (public_key, timestamp) = line
+            .split_once('\t')
+            .context("Unexpected output of wg show")?;
+        let timestamp = timestamp.trim().parse::<u64>()?;
+        if timestamp == 0 {
+            continue;
+        }
+        handshakes.insert(
+            Pubkey::from_base64(public_key)?,
+            SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(timestamp),
+        );
+    }
+    Ok(handshakes)
+}
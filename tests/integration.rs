@@ -1,20 +1,51 @@
 mod fixtures;
 mod utils;
 
-use std::{collections::HashSet, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    time::{Duration, SystemTime},
+};
 
 use anyhow::{ensure, Result};
 use assert_fs::TempDir;
+use base64::prelude::{Engine as _, BASE64_STANDARD};
 use configparser::ini::Ini;
 use fixtures::{consul, federated_consul_cluster, tmpdir, ConsulContainer};
+use ipnet::IpNet;
+use port_check::free_local_port;
 use pretty_assertions::assert_eq;
 use rand::seq::SliceRandom;
 use rstest::rstest;
 use tokio::{process::Command, time::sleep};
+use tokio_util::sync::CancellationToken;
 use wireguard_keys::Privkey;
-use wiresmith::{networkd::NetworkdConfiguration, wireguard::WgPeer, CONSUL_TTL};
-
-use crate::{utils::wait_for_files, utils::WiresmithContainer};
+use wiresmith::{
+    allocation::{AddressAllocator, AddressRange, AddressUsage, AllocationStrategy},
+    client_config::ClientConfig,
+    consul::{PeerRecord, PeerState, PEER_RECORD_VERSION},
+    endpoint::{
+        select_endpoint_ips, with_default_port, EndpointCandidate, EndpointSelector, IpFamily,
+    },
+    enrolment::{self, Approval, EnrolmentState, Enrolments, JoinClaim, JoinToken},
+    lease::{AddressLease, LeaseIdentity},
+    metadata::NodeMetadata,
+    networkd::{LinkOptions, NetworkdConfiguration},
+    pmtu::{PathMtuDiscovery, DEFAULT_MAX_MTU, MIN_MTU},
+    policy::{PeeringPolicy, Tags},
+    relay::RelayFallback,
+    signing::{AuthorityKey, Enrolment, MeshAuthority},
+    static_peers::{self, StaticPeer},
+    stun,
+    topology::{self, NodeRole, Topology},
+    wireguard::{KeepalivePolicy, WgPeer},
+    CONSUL_TTL,
+};
+
+use crate::{
+    utils::wait_for_files,
+    utils::{StunResponder, WiresmithContainer},
+};
 
 /// If the address is provided explicitly, it needs to be contained within network.
 #[rstest]
@@ -173,11 +204,11 @@ async fn initial_configuration(#[future] consul: ConsulContainer, tmpdir: TempDi
     // Check the config put into Consul.
     let peers = consul.client.get_peers().await?;
     let mut expected_peers = HashSet::new();
-    expected_peers.insert(WgPeer {
-        public_key: private_key.pubkey(),
-        endpoint: format!("initial-{}:51820", consul.http_port),
-        address: "10.0.0.1/32".parse().unwrap(),
-    });
+    expected_peers.insert(WgPeer::new(
+        private_key.pubkey(),
+        &format!("initial-{}:51820", consul.http_port),
+        "10.0.0.1".parse().unwrap(),
+    ));
 
     // Now there should be peers.
     assert_eq!(peers, expected_peers);
@@ -249,18 +280,18 @@ async fn join_network(
     // We don't expect to see ourselves in the list of peers as we don't want to peer with
     // ourselves.
     let mut expected_peers_a = HashSet::new();
-    expected_peers_a.insert(WgPeer {
-        public_key: networkd_config_b.public_key,
-        endpoint: format!("b-{}:51820", consul.http_port),
-        address: "10.0.0.2/32".parse().unwrap(),
-    });
+    expected_peers_a.insert(WgPeer::new(
+        networkd_config_b.public_key,
+        &format!("b-{}:51820", consul.http_port),
+        "10.0.0.2".parse().unwrap(),
+    ));
 
     let mut expected_peers_b = HashSet::new();
-    expected_peers_b.insert(WgPeer {
-        public_key: networkd_config_a.public_key,
-        endpoint: format!("a-{}:51820", consul.http_port),
-        address: "10.0.0.1/32".parse().unwrap(),
-    });
+    expected_peers_b.insert(WgPeer::new(
+        networkd_config_a.public_key,
+        &format!("a-{}:51820", consul.http_port),
+        "10.0.0.1".parse().unwrap(),
+    ));
     assert_eq!(networkd_config_a.peers, expected_peers_a);
     assert_eq!(networkd_config_b.peers, expected_peers_b);
 
@@ -303,40 +334,40 @@ async fn join_network(
 
     // We recheck that now everyone has everyone else but not themselves.
     let mut expected_peers_a = HashSet::new();
-    expected_peers_a.insert(WgPeer {
-        public_key: networkd_config_b.public_key,
-        endpoint: format!("b-{}:51820", consul.http_port),
-        address: "10.0.0.2/32".parse().unwrap(),
-    });
-    expected_peers_a.insert(WgPeer {
-        public_key: networkd_config_c.public_key,
-        endpoint: format!("c-{}:51820", consul.http_port),
-        address: "10.0.0.3/32".parse().unwrap(),
-    });
+    expected_peers_a.insert(WgPeer::new(
+        networkd_config_b.public_key,
+        &format!("b-{}:51820", consul.http_port),
+        "10.0.0.2".parse().unwrap(),
+    ));
+    expected_peers_a.insert(WgPeer::new(
+        networkd_config_c.public_key,
+        &format!("c-{}:51820", consul.http_port),
+        "10.0.0.3".parse().unwrap(),
+    ));
 
     let mut expected_peers_b = HashSet::new();
-    expected_peers_b.insert(WgPeer {
-        public_key: networkd_config_a.public_key,
-        endpoint: format!("a-{}:51820", consul.http_port),
-        address: "10.0.0.1/32".parse().unwrap(),
-    });
-    expected_peers_b.insert(WgPeer {
-        public_key: networkd_config_c.public_key,
-        endpoint: format!("c-{}:51820", consul.http_port),
-        address: "10.0.0.3/32".parse().unwrap(),
-    });
+    expected_peers_b.insert(WgPeer::new(
+        networkd_config_a.public_key,
+        &format!("a-{}:51820", consul.http_port),
+        "10.0.0.1".parse().unwrap(),
+    ));
+    expected_peers_b.insert(WgPeer::new(
+        networkd_config_c.public_key,
+        &format!("c-{}:51820", consul.http_port),
+        "10.0.0.3".parse().unwrap(),
+    ));
 
     let mut expected_peers_c = HashSet::new();
-    expected_peers_c.insert(WgPeer {
-        public_key: networkd_config_a.public_key,
-        endpoint: format!("a-{}:51820", consul.http_port),
-        address: "10.0.0.1/32".parse().unwrap(),
-    });
-    expected_peers_c.insert(WgPeer {
-        public_key: networkd_config_b.public_key,
-        endpoint: format!("b-{}:51820", consul.http_port),
-        address: "10.0.0.2/32".parse().unwrap(),
-    });
+    expected_peers_c.insert(WgPeer::new(
+        networkd_config_a.public_key,
+        &format!("a-{}:51820", consul.http_port),
+        "10.0.0.1".parse().unwrap(),
+    ));
+    expected_peers_c.insert(WgPeer::new(
+        networkd_config_b.public_key,
+        &format!("b-{}:51820", consul.http_port),
+        "10.0.0.2".parse().unwrap(),
+    ));
     assert_eq!(networkd_config_a.peers, expected_peers_a);
     assert_eq!(networkd_config_b.peers, expected_peers_b);
     assert_eq!(networkd_config_c.peers, expected_peers_c);
@@ -357,6 +388,82 @@ async fn join_network(
     Ok(())
 }
 
+/// A broken peering policy in Consul doesn't make the nodes drop their peers.
+#[rstest]
+#[tokio::test]
+async fn keeps_peers_with_invalid_policy(
+    #[future] consul: ConsulContainer,
+    #[from(tmpdir)] tmpdir_a: TempDir,
+    #[from(tmpdir)] tmpdir_b: TempDir,
+) -> Result<()> {
+    let consul = consul.await;
+
+    let _wiresmith_a = WiresmithContainer::new(
+        "a",
+        "10.0.0.0/24",
+        &format!("wiresmith-{}", consul.http_port),
+        consul.http_port,
+        &["--update-period", "1s"],
+        &tmpdir_a,
+    )
+    .await;
+    wait_for_files(vec![
+        tmpdir_a.join("wg0.network").as_path(),
+        tmpdir_a.join("wg0.netdev").as_path(),
+    ])
+    .await;
+
+    let _wiresmith_b = WiresmithContainer::new(
+        "b",
+        "10.0.0.0/24",
+        &format!("wiresmith-{}", consul.http_port),
+        consul.http_port,
+        &["--update-period", "1s"],
+        &tmpdir_b,
+    )
+    .await;
+    wait_for_files(vec![
+        tmpdir_b.join("wg0.network").as_path(),
+        tmpdir_b.join("wg0.netdev").as_path(),
+    ])
+    .await;
+    sleep(Duration::from_secs(2)).await;
+
+    consul
+        .client
+        .http_client
+        .put(consul.client.kv_api_base_url.join("policy")?)
+        .body("not json")
+        .send()
+        .await?
+        .error_for_status()?;
+
+    // Give the nodes a few chances to pick up the policy.
+    sleep(Duration::from_secs(3)).await;
+
+    let networkd_config_a = NetworkdConfiguration::from_config(&tmpdir_a, "wg0").await?;
+    let networkd_config_b = NetworkdConfiguration::from_config(&tmpdir_b, "wg0").await?;
+    assert_eq!(
+        networkd_config_a
+            .peers
+            .iter()
+            .map(|x| x.public_key)
+            .collect::<Vec<_>>(),
+        vec![networkd_config_b.public_key]
+    );
+    assert_eq!(
+        networkd_config_b
+            .peers
+            .iter()
+            .map(|x| x.public_key)
+            .collect::<Vec<_>>(),
+        vec![networkd_config_a.public_key]
+    );
+    assert_eq!(consul.client.get_peers().await?.len(), 2);
+
+    Ok(())
+}
+
 /// A peer is added to the first Consul server in dc1 which is federated to a second Consul server
 /// in dc2. Afterwards, a second peer joins on the second Consul server.
 #[rstest]
@@ -413,16 +520,16 @@ async fn join_network_federated_cluster(
     let networkd_config_b = NetworkdConfiguration::from_config(&tmpdir_b, "wg0").await?;
 
     let mut expected_peers = HashSet::new();
-    expected_peers.insert(WgPeer {
-        public_key: networkd_config_a.public_key,
-        endpoint: format!("a-{}:51820", consul_dc1.http_port),
-        address: "10.0.0.1/32".parse().unwrap(),
-    });
-    expected_peers.insert(WgPeer {
-        public_key: networkd_config_b.public_key,
-        endpoint: format!("b-{}:51820", consul_dc2.http_port),
-        address: "10.0.0.2/32".parse().unwrap(),
-    });
+    expected_peers.insert(WgPeer::new(
+        networkd_config_a.public_key,
+        &format!("a-{}:51820", consul_dc1.http_port),
+        "10.0.0.1".parse().unwrap(),
+    ));
+    expected_peers.insert(WgPeer::new(
+        networkd_config_b.public_key,
+        &format!("b-{}:51820", consul_dc2.http_port),
+        "10.0.0.2".parse().unwrap(),
+    ));
 
     // Peers in Consul should be union the other peer lists.
     let consul_peers_dc1 = consul_dc1.client.get_peers().await?;
@@ -432,6 +539,17 @@ async fn join_network_federated_cluster(
     let consul_peers_dc2 = consul_dc2.client.get_peers().await?;
     assert_eq!(consul_peers_dc2, consul_peers_dc1);
 
+    // Each peer should know the datacenter its config lives in.
+    let datacenters = consul_peers_dc1
+        .iter()
+        .map(|peer| (peer.address, peer.datacenter.as_deref()))
+        .collect::<HashSet<_>>();
+    let expected_datacenters = HashSet::from([
+        ("10.0.0.1/32".parse()?, Some("dc1")),
+        ("10.0.0.2/32".parse()?, Some("dc2")),
+    ]);
+    assert_eq!(datacenters, expected_datacenters);
+
     Ok(())
 }
 
@@ -467,11 +585,11 @@ async fn deletes_peer_on_timeout(
     let networkd_config_a = NetworkdConfiguration::from_config(&tmpdir_a, "wg0").await?;
     peers.push((
         wiresmith_a,
-        WgPeer {
-            public_key: networkd_config_a.public_key,
-            endpoint: format!("a-{}:51820", consul.http_port),
-            address: "10.0.0.1/32".parse().unwrap(),
-        },
+        WgPeer::new(
+            networkd_config_a.public_key,
+            &format!("a-{}:51820", consul.http_port),
+            "10.0.0.1".parse().unwrap(),
+        ),
     ));
 
     let wiresmith_b = WiresmithContainer::new(
@@ -492,11 +610,11 @@ async fn deletes_peer_on_timeout(
     let networkd_config_b = NetworkdConfiguration::from_config(&tmpdir_b, "wg0").await?;
     peers.push((
         wiresmith_b,
-        WgPeer {
-            public_key: networkd_config_b.public_key,
-            endpoint: format!("b-{}:51820", consul.http_port),
-            address: "10.0.0.2/32".parse().unwrap(),
-        },
+        WgPeer::new(
+            networkd_config_b.public_key,
+            &format!("b-{}:51820", consul.http_port),
+            "10.0.0.2".parse().unwrap(),
+        ),
     ));
 
     let wiresmith_c = WiresmithContainer::new(
@@ -517,11 +635,11 @@ async fn deletes_peer_on_timeout(
     let networkd_config_c = NetworkdConfiguration::from_config(&tmpdir_c, "wg0").await?;
     peers.push((
         wiresmith_c,
-        WgPeer {
-            public_key: networkd_config_c.public_key,
-            endpoint: format!("c-{}:51820", consul.http_port),
-            address: "10.0.0.3/32".parse().unwrap(),
-        },
+        WgPeer::new(
+            networkd_config_c.public_key,
+            &format!("c-{}:51820", consul.http_port),
+            "10.0.0.3".parse().unwrap(),
+        ),
     ));
 
     // Wait for consul to pickup changes.
@@ -556,3 +674,2035 @@ async fn deletes_peer_on_timeout(
 
     Ok(())
 }
+
+/// Sections and keys in the networkd files that wiresmith doesn't manage are preserved when it
+/// writes its configuration.
+#[rstest]
+#[tokio::test]
+async fn preserves_user_customisations(
+    #[future] consul: ConsulContainer,
+    tmpdir: TempDir,
+) -> Result<()> {
+    let consul = consul.await;
+
+    let network_file = tmpdir.join("wg0.network");
+    let netdev_file = tmpdir.join("wg0.netdev");
+
+    // An admin already added some customisations before wiresmith was started.
+    std::fs::write(
+        &network_file,
+        "[Network]\nIPv6AcceptRA=no\n\n[Route]\nDestination=192.168.0.0/24\n",
+    )?;
+    std::fs::write(&netdev_file, "[NetDev]\nDescription=Our mesh\n")?;
+
+    let _wiresmith = WiresmithContainer::new(
+        "custom",
+        "10.0.0.0/24",
+        &format!("wiresmith-{}", consul.http_port),
+        consul.http_port,
+        &[],
+        &tmpdir,
+    )
+    .await;
+
+    // Wait until wiresmith has merged its config into the existing files.
+    sleep(Duration::from_secs(2)).await;
+
+    let network_ini = ini::Ini::load_from_file(network_file)?;
+    assert_eq!(
+        network_ini
+            .section(Some("Network"))
+            .unwrap()
+            .get("Address")
+            .unwrap(),
+        "10.0.0.1/24"
+    );
+    assert_eq!(
+        network_ini
+            .section(Some("Network"))
+            .unwrap()
+            .get("IPv6AcceptRA")
+            .unwrap(),
+        "no"
+    );
+    assert_eq!(
+        network_ini
+            .section(Some("Route"))
+            .unwrap()
+            .get("Destination")
+            .unwrap(),
+        "192.168.0.0/24"
+    );
+
+    let netdev_ini = ini::Ini::load_from_file(netdev_file)?;
+    assert_eq!(
+        netdev_ini
+            .section(Some("NetDev"))
+            .unwrap()
+            .get("Description")
+            .unwrap(),
+        "Our mesh"
+    );
+    assert_eq!(
+        netdev_ini
+            .section(Some("NetDev"))
+            .unwrap()
+            .get("Kind")
+            .unwrap(),
+        "wireguard"
+    );
+
+    Ok(())
+}
+
+/// Interface options passed on the command line end up in the networkd config.
+#[rstest]
+#[tokio::test]
+async fn interface_options(#[future] consul: ConsulContainer, tmpdir: TempDir) -> Result<()> {
+    let consul = consul.await;
+
+    let _wiresmith = WiresmithContainer::new(
+        "options",
+        "10.0.0.0/24",
+        &format!("wiresmith-{}", consul.http_port),
+        consul.http_port,
+        &[
+            "--mtu",
+            "1420",
+            "--fwmark",
+            "51820",
+            "--route-table",
+            "1000",
+            "--route-metric",
+            "10",
+        ],
+        &tmpdir,
+    )
+    .await;
+
+    let network_file = tmpdir.join("wg0.network");
+    let netdev_file = tmpdir.join("wg0.netdev");
+
+    wait_for_files(vec![network_file.as_path(), netdev_file.as_path()]).await;
+
+    let networkd_config = NetworkdConfiguration::from_config(&tmpdir, "wg0").await?;
+    assert_eq!(
+        networkd_config.link_options,
+        LinkOptions {
+            mtu: Some(1420),
+            fwmark: Some(51820),
+            route_table: Some("1000".to_string()),
+            route_metric: Some(10),
+        }
+    );
+
+    Ok(())
+}
+
+/// With path MTU discovery enabled, the interface MTU is set to the largest value that works with
+/// all peers and is published to Consul.
+#[rstest]
+#[tokio::test]
+async fn path_mtu_discovery(
+    #[future] consul: ConsulContainer,
+    #[from(tmpdir)] tmpdir_a: TempDir,
+    #[from(tmpdir)] tmpdir_b: TempDir,
+) -> Result<()> {
+    let consul = consul.await;
+    let args = &["--update-period", "1s", "--pmtu-discovery", "--mtu", "1380"];
+
+    let _wiresmith_a = WiresmithContainer::new(
+        "a",
+        "10.0.0.0/24",
+        &format!("wiresmith-{}", consul.http_port),
+        consul.http_port,
+        args,
+        &tmpdir_a,
+    )
+    .await;
+
+    let network_file_a = tmpdir_a.join("wg0.network");
+    let netdev_file_a = tmpdir_a.join("wg0.netdev");
+
+    wait_for_files(vec![network_file_a.as_path(), netdev_file_a.as_path()]).await;
+
+    let _wiresmith_b = WiresmithContainer::new(
+        "b",
+        "10.0.0.0/24",
+        &format!("wiresmith-{}", consul.http_port),
+        consul.http_port,
+        args,
+        &tmpdir_b,
+    )
+    .await;
+
+    let network_file_b = tmpdir_b.join("wg0.network");
+    let netdev_file_b = tmpdir_b.join("wg0.netdev");
+
+    wait_for_files(vec![network_file_b.as_path(), netdev_file_b.as_path()]).await;
+
+    // Wait for both sides to probe each other.
+    sleep(Duration::from_secs(3)).await;
+
+    // The container network has an MTU of 1500 so the upper bound is what we should end up with.
+    let networkd_config_a = NetworkdConfiguration::from_config(&tmpdir_a, "wg0").await?;
+    let networkd_config_b = NetworkdConfiguration::from_config(&tmpdir_b, "wg0").await?;
+    assert_eq!(networkd_config_a.link_options.mtu, Some(1380));
+    assert_eq!(networkd_config_b.link_options.mtu, Some(1380));
+
+    let consul_peers = consul.client.get_peers().await?;
+    assert_eq!(consul_peers.len(), 2);
+    assert!(consul_peers.iter().all(|peer| peer.mtu == Some(1380)));
+
+    // A peer that can't be probed restricts the MTU to what it published itself.
+    let mut unprobeable = WgPeer::new(
+        Privkey::generate().pubkey(),
+        "192.0.2.1:51820",
+        "10.0.0.100".parse()?,
+    );
+    unprobeable.mtu = Some(1340);
+    consul.client.add_external_peer(&unprobeable, None).await?;
+
+    // The pings to the unreachable peer time out before the result comes in.
+    sleep(Duration::from_secs(5)).await;
+
+    let networkd_config_a = NetworkdConfiguration::from_config(&tmpdir_a, "wg0").await?;
+    let networkd_config_b = NetworkdConfiguration::from_config(&tmpdir_b, "wg0").await?;
+    assert_eq!(networkd_config_a.link_options.mtu, Some(1340));
+    assert_eq!(networkd_config_b.link_options.mtu, Some(1340));
+
+    Ok(())
+}
+
+/// Peers that can't be probed and didn't publish an MTU keep the current MTU as the path to them
+/// was never verified. Peers without an endpoint aren't taken into account at all.
+#[rstest]
+#[tokio::test]
+async fn unprobeable_peers_keep_mtu() -> Result<()> {
+    let mut pmtu = PathMtuDiscovery::new(DEFAULT_MAX_MTU, Duration::from_secs(600));
+    let mut published = WgPeer::new(
+        Privkey::generate().pubkey(),
+        "192.0.2.1:51820",
+        "10.0.0.1".parse()?,
+    );
+    published.mtu = Some(1340);
+    let mut peers = HashSet::from([published.clone()]);
+    assert_eq!(pmtu.safe_mtu(&peers, 1380).await, 1340);
+
+    let unpublished = WgPeer::new(
+        Privkey::generate().pubkey(),
+        "192.0.2.2:51820",
+        "10.0.0.2".parse()?,
+    );
+    peers.insert(unpublished.clone());
+    assert_eq!(pmtu.safe_mtu(&peers, 1340).await, 1340);
+
+    // Without the peer that published its MTU, the unverified peer doesn't let the MTU go up.
+    peers.remove(&published);
+    assert_eq!(pmtu.safe_mtu(&peers, 1340).await, 1340);
+    assert_eq!(pmtu.safe_mtu(&peers, 1400).await, 1400);
+
+    let mut passive = WgPeer::new(Privkey::generate().pubkey(), "", "10.0.0.3".parse()?);
+    passive.mtu = Some(MIN_MTU);
+    assert_eq!(
+        pmtu.safe_mtu(&HashSet::from([passive]), 1340).await,
+        DEFAULT_MAX_MTU
+    );
+
+    assert_eq!(pmtu.safe_mtu(&HashSet::new(), 1340).await, DEFAULT_MAX_MTU);
+
+    Ok(())
+}
+
+/// Peers advertise the keepalive they need and both sides of a tunnel agree on the shorter one.
+#[rstest]
+#[tokio::test]
+async fn per_peer_keepalive(
+    #[future] consul: ConsulContainer,
+    #[from(tmpdir)] tmpdir_a: TempDir,
+    #[from(tmpdir)] tmpdir_b: TempDir,
+) -> Result<()> {
+    let consul = consul.await;
+
+    // a is publicly reachable whereas b is behind NAT.
+    let _wiresmith_a = WiresmithContainer::new(
+        "a",
+        "10.0.0.0/24",
+        &format!("wiresmith-{}", consul.http_port),
+        consul.http_port,
+        &["--update-period", "1s", "--advertise-keepalive", "0s"],
+        &tmpdir_a,
+    )
+    .await;
+
+    let network_file_a = tmpdir_a.join("wg0.network");
+    let netdev_file_a = tmpdir_a.join("wg0.netdev");
+
+    wait_for_files(vec![network_file_a.as_path(), netdev_file_a.as_path()]).await;
+
+    let _wiresmith_b = WiresmithContainer::new(
+        "b",
+        "10.0.0.0/24",
+        &format!("wiresmith-{}", consul.http_port),
+        consul.http_port,
+        &["--update-period", "1s", "--advertise-keepalive", "10s"],
+        &tmpdir_b,
+    )
+    .await;
+
+    let network_file_b = tmpdir_b.join("wg0.network");
+    let netdev_file_b = tmpdir_b.join("wg0.netdev");
+
+    wait_for_files(vec![network_file_b.as_path(), netdev_file_b.as_path()]).await;
+
+    // Wait until the first client has had a chance to pick up the changes and generate a new
+    // config.
+    sleep(Duration::from_secs(2)).await;
+
+    for netdev_file in [netdev_file_a, netdev_file_b] {
+        let netdev_ini = ini::Ini::load_from_file(netdev_file)?;
+        assert_eq!(
+            netdev_ini
+                .section(Some("WireGuardPeer"))
+                .unwrap()
+                .get("PersistentKeepalive")
+                .unwrap(),
+            "10"
+        );
+    }
+
+    Ok(())
+}
+
+/// The public endpoint of the WireGuard listen port is discovered via STUN as long as the port
+/// isn't in use yet. Afterwards, only the public address can be discovered.
+#[rstest]
+#[tokio::test]
+async fn stun_endpoint_discovery() -> Result<()> {
+    let mapped_address = "203.0.113.7:40000".parse()?;
+    let stun = StunResponder::new(mapped_address).await;
+
+    let listen_port = free_local_port().expect("Couldn't find a free local port");
+    let endpoint = stun::discover_endpoint(&stun.address.to_string(), listen_port).await?;
+    assert_eq!(endpoint, Some(mapped_address));
+
+    // Pretend that WireGuard is already listening on the port.
+    let _socket = std::net::UdpSocket::bind(("0.0.0.0", listen_port))?;
+    let endpoint = stun::discover_endpoint(&stun.address.to_string(), listen_port).await?;
+    assert_eq!(endpoint, None);
+    let address = stun::discover_address(&stun.address.to_string()).await?;
+    assert_eq!(address, mapped_address.ip());
+
+    Ok(())
+}
+
+/// Endpoint addresses are chosen by scope and family. Temporary IPv6 addresses are never used.
+#[rstest]
+#[case(&["fe80::1", "fd00::1", "2001:db8::1"], None, &["2001:db8::1"])]
+#[case(&["10.0.0.1", "fe80::1", "2001:db8::1"], None, &["2001:db8::1", "10.0.0.1"])]
+#[case(&["10.0.0.1", "203.0.113.1", "2001:db8::1"], None, &["203.0.113.1", "2001:db8::1"])]
+#[case(&["203.0.113.1", "2001:db8::1"], Some(IpFamily::V6), &["2001:db8::1"])]
+#[case(&["2001:db8::2", "2001:db8::1"], None, &["2001:db8::1"])]
+#[case(&["127.0.0.1", "::1"], None, &[])]
+fn endpoint_ip_selection(
+    #[case] ips: &[&str],
+    #[case] family: Option<IpFamily>,
+    #[case] expected: &[&str],
+) {
+    let ips = ips.iter().map(|x| x.parse().unwrap()).collect::<Vec<_>>();
+    let temporary = HashSet::from(["2001:db8::2".parse().unwrap()]);
+    let expected = expected
+        .iter()
+        .map(|x| x.parse().unwrap())
+        .collect::<Vec<IpAddr>>();
+    assert_eq!(select_endpoint_ips(&ips, family, &temporary), expected);
+}
+
+/// Endpoint candidates are parsed from LABEL:PRIORITY=ENDPOINT.
+#[rstest]
+#[case("private:10=10.1.0.5", Some(("10.1.0.5", Some("private"), 10)))]
+#[case(":50=[2001:db8::1]:51820", Some(("[2001:db8::1]:51820", None, 50)))]
+#[case("private=10.1.0.5", None)]
+#[case("private:ten=10.1.0.5", None)]
+#[case("private:10=", None)]
+fn endpoint_candidate_parsing(
+    #[case] input: &str,
+    #[case] expected: Option<(&str, Option<&str>, u32)>,
+) {
+    let expected = expected.map(|(endpoint, label, priority)| EndpointCandidate {
+        endpoint: endpoint.to_string(),
+        label: label.map(str::to_string),
+        priority,
+    });
+    assert_eq!(input.parse::<EndpointCandidate>().ok(), expected);
+}
+
+/// The port is only added to endpoints that don't have one yet.
+#[rstest]
+#[case("10.1.0.5", "10.1.0.5:51820")]
+#[case("10.1.0.5:1234", "10.1.0.5:1234")]
+#[case("fd00::1", "[fd00::1]:51820")]
+#[case("[fd00::1]", "[fd00::1]:51820")]
+#[case("[fd00::1]:1234", "[fd00::1]:1234")]
+#[case("wg.example.com", "wg.example.com:51820")]
+#[case("wg.example.com:1234", "wg.example.com:1234")]
+fn endpoint_default_port(#[case] endpoint: &str, #[case] expected: &str) {
+    assert_eq!(with_default_port(endpoint, 51820), expected);
+}
+
+/// Candidates are tried by priority and we fall back to the next one if there is no handshake.
+#[rstest]
+fn endpoint_candidate_fallback() {
+    let mut peer = WgPeer::new(
+        Privkey::generate().pubkey(),
+        "203.0.113.1:51820",
+        "10.0.0.2".parse().unwrap(),
+    );
+    peer.endpoint_candidates = vec![
+        EndpointCandidate {
+            endpoint: "10.1.0.5:51820".to_string(),
+            label: Some("private".to_string()),
+            priority: 10,
+        },
+        EndpointCandidate {
+            endpoint: "[2001:db8::1]:51820".to_string(),
+            label: None,
+            priority: 1,
+        },
+    ];
+    let peers = HashSet::from([peer.clone()]);
+    let own_families = HashSet::from([IpFamily::V4]);
+    let selected_endpoint = |peers: HashSet<WgPeer>| peers.into_iter().next().unwrap().endpoint;
+    let keepalive = KeepalivePolicy {
+        default: 25,
+        own: None,
+    };
+
+    // Without handshake information, we stick to the most preferred reachable candidate.
+    let mut selector = EndpointSelector::new(Duration::ZERO);
+    let endpoint =
+        selected_endpoint(selector.select(peers.clone(), &own_families, None, keepalive, None));
+    assert_eq!(endpoint, "10.1.0.5:51820");
+    let endpoint =
+        selected_endpoint(selector.select(peers.clone(), &own_families, None, keepalive, None));
+    assert_eq!(endpoint, "10.1.0.5:51820");
+
+    // Without a handshake, we fall back to the next candidate and eventually start over.
+    let no_handshakes = HashMap::new();
+    let endpoint = selected_endpoint(selector.select(
+        peers.clone(),
+        &own_families,
+        None,
+        keepalive,
+        Some(&no_handshakes),
+    ));
+    assert_eq!(endpoint, "203.0.113.1:51820");
+    let endpoint = selected_endpoint(selector.select(
+        peers.clone(),
+        &own_families,
+        None,
+        keepalive,
+        Some(&no_handshakes),
+    ));
+    assert_eq!(endpoint, "10.1.0.5:51820");
+
+    // As long as there are handshakes, we keep the current candidate.
+    let mut selector = EndpointSelector::new(Duration::from_secs(60));
+    selector.select(
+        peers.clone(),
+        &own_families,
+        None,
+        keepalive,
+        Some(&no_handshakes),
+    );
+    let handshakes = HashMap::from([(peer.public_key, SystemTime::now())]);
+    let endpoint = selected_endpoint(selector.select(
+        peers.clone(),
+        &own_families,
+        None,
+        keepalive,
+        Some(&handshakes),
+    ));
+    assert_eq!(endpoint, "10.1.0.5:51820");
+
+    // Once the handshakes stop, we fall back even though the candidate used to work.
+    let mut selector = EndpointSelector::new(Duration::from_millis(200));
+    selector.select(
+        peers.clone(),
+        &own_families,
+        None,
+        keepalive,
+        Some(&no_handshakes),
+    );
+    std::thread::sleep(Duration::from_millis(100));
+    let handshakes = HashMap::from([(peer.public_key, SystemTime::now())]);
+    std::thread::sleep(Duration::from_millis(150));
+    let endpoint = selected_endpoint(selector.select(
+        peers.clone(),
+        &own_families,
+        None,
+        keepalive,
+        Some(&handshakes),
+    ));
+    assert_eq!(endpoint, "10.1.0.5:51820");
+    std::thread::sleep(Duration::from_millis(100));
+    let endpoint = selected_endpoint(selector.select(
+        peers.clone(),
+        &own_families,
+        None,
+        keepalive,
+        Some(&handshakes),
+    ));
+    assert_eq!(endpoint, "203.0.113.1:51820");
+
+    // Without a keepalive, there are no handshakes on an idle tunnel so we keep the candidate.
+    let no_keepalive = KeepalivePolicy {
+        default: 0,
+        own: None,
+    };
+    let mut selector = EndpointSelector::new(Duration::ZERO);
+    let endpoint = selected_endpoint(selector.select(
+        peers.clone(),
+        &own_families,
+        None,
+        no_keepalive,
+        Some(&no_handshakes),
+    ));
+    assert_eq!(endpoint, "10.1.0.5:51820");
+    let endpoint = selected_endpoint(selector.select(
+        peers,
+        &own_families,
+        None,
+        no_keepalive,
+        Some(&no_handshakes),
+    ));
+    assert_eq!(endpoint, "10.1.0.5:51820");
+}
+
+/// Peers in the same datacenter only use the datacenter endpoint while peers in other datacenters
+/// use the public endpoints.
+#[rstest]
+#[case(Some("dc1"), "10.1.0.5:51820")]
+#[case(Some("dc2"), "203.0.113.1:51820")]
+#[case(None, "203.0.113.1:51820")]
+fn datacenter_endpoint_selection(#[case] own_datacenter: Option<&str>, #[case] expected: &str) {
+    let mut peer = WgPeer::new(
+        Privkey::generate().pubkey(),
+        "203.0.113.1:51820",
+        "10.0.0.2".parse().unwrap(),
+    );
+    peer.datacenter_endpoint = Some("10.1.0.5:51820".to_string());
+    peer.datacenter = Some("dc1".to_string());
+
+    let mut selector = EndpointSelector::new(Duration::ZERO);
+    let peers = selector.select(
+        HashSet::from([peer]),
+        &HashSet::from([IpFamily::V4]),
+        own_datacenter,
+        KeepalivePolicy {
+            default: 25,
+            own: None,
+        },
+        Some(&HashMap::new()),
+    );
+    let endpoint = peers.into_iter().next().unwrap().endpoint;
+    assert_eq!(endpoint, expected);
+}
+
+/// Spokes only peer with hubs and route the whole network via the hub with the lowest address.
+/// Hubs and nodes in a mesh peer with everyone.
+#[rstest]
+#[case(Topology::Mesh, Some(NodeRole::Spoke), &[("10.0.0.1/32", Some(NodeRole::Hub)), ("10.0.0.2/32", Some(NodeRole::Hub)), ("10.0.0.3/32", Some(NodeRole::Spoke)), ("10.0.0.4/32", None)])]
+#[case(Topology::HubAndSpoke, Some(NodeRole::Hub), &[("10.0.0.1/32", Some(NodeRole::Hub)), ("10.0.0.2/32", Some(NodeRole::Hub)), ("10.0.0.3/32", Some(NodeRole::Spoke)), ("10.0.0.4/32", None)])]
+#[case(Topology::HubAndSpoke, Some(NodeRole::Spoke), &[("10.0.0.0/24", Some(NodeRole::Hub)), ("10.0.0.2/32", Some(NodeRole::Hub)), ("10.0.0.4/32", None)])]
+fn hub_and_spoke_topology(
+    #[case] topology: Topology,
+    #[case] own_role: Option<NodeRole>,
+    #[case] expected: &[(&str, Option<NodeRole>)],
+) {
+    let peers = [
+        ("10.0.0.2", Some(NodeRole::Hub)),
+        ("10.0.0.1", Some(NodeRole::Hub)),
+        ("10.0.0.3", Some(NodeRole::Spoke)),
+        ("10.0.0.4", None),
+    ]
+    .into_iter()
+    .map(|(address, role)| {
+        let mut peer = WgPeer::new(
+            Privkey::generate().pubkey(),
+            &format!("{address}:51820"),
+            address.parse().unwrap(),
+        );
+        peer.role = role;
+        peer
+    })
+    .collect::<HashSet<_>>();
+
+    let peers = topology.peers_for(own_role, "10.0.0.5/24".parse().unwrap(), peers);
+    let allowed_ips = peers
+        .iter()
+        .map(|x| (x.address, x.role))
+        .collect::<HashSet<_>>();
+    let expected = expected
+        .iter()
+        .map(|(address, role)| (address.parse().unwrap(), *role))
+        .collect::<HashSet<_>>();
+    assert_eq!(allowed_ips, expected);
+}
+
+/// Peers without a role are never chosen as the gateway, even if they have the lowest address.
+#[rstest]
+fn hub_and_spoke_roleless_peers() {
+    let peers = [
+        ("10.0.0.1", None),
+        ("10.0.0.2", Some(NodeRole::Hub)),
+        ("10.0.0.3", Some(NodeRole::Spoke)),
+    ]
+    .into_iter()
+    .map(|(address, role)| {
+        let mut peer = WgPeer::new(Privkey::generate().pubkey(), "", address.parse().unwrap());
+        peer.role = role;
+        peer
+    })
+    .collect::<HashSet<_>>();
+
+    let peers = Topology::HubAndSpoke.peers_for(
+        Some(NodeRole::Spoke),
+        "10.0.0.5/24".parse().unwrap(),
+        peers,
+    );
+    let allowed_ips = peers
+        .iter()
+        .map(|x| (x.address, x.role))
+        .collect::<HashSet<_>>();
+    assert_eq!(
+        allowed_ips,
+        HashSet::from([
+            ("10.0.0.1/32".parse().unwrap(), None),
+            ("10.0.0.0/24".parse().unwrap(), Some(NodeRole::Hub)),
+        ])
+    );
+}
+
+/// Tag selectors need to match all of their tags and rules apply in both directions.
+#[rstest]
+#[case(&[("env", "prod")], &[("env", "prod"), ("role", "db")], true)]
+#[case(&[("env", "prod")], &[("env", "staging")], false)]
+#[case(&[("env", "staging")], &[("env", "staging")], false)]
+#[case(&[("env", "staging"), ("role", "bastion")], &[("env", "prod")], true)]
+#[case(&[("env", "prod")], &[("env", "staging"), ("role", "bastion")], true)]
+#[case(&[], &[("env", "prod")], false)]
+fn peering_policy(#[case] a: &[(&str, &str)], #[case] b: &[(&str, &str)], #[case] allowed: bool) {
+    let policy: PeeringPolicy = serde_json::from_str(
+        r#"{"allow": [
+            {"between": [{"env": "prod"}, {"env": "prod"}]},
+            {"between": [{"env": "staging", "role": "bastion"}, {}]}
+        ]}"#,
+    )
+    .unwrap();
+    let tags = |x: &[(&str, &str)]| -> Tags {
+        x.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    };
+    assert_eq!(policy.allows(&tags(a), &tags(b)), allowed);
+    assert_eq!(policy.allows(&tags(b), &tags(a)), allowed);
+}
+
+/// Peers we don't get a handshake with are routed via a relay until they're reachable directly
+/// again.
+#[rstest]
+fn relay_fallback() {
+    let mut relay = WgPeer::new(
+        Privkey::generate().pubkey(),
+        "203.0.113.1:51820",
+        "10.0.0.1".parse().unwrap(),
+    );
+    relay.relay = true;
+    let peer = WgPeer::new(
+        Privkey::generate().pubkey(),
+        "203.0.113.2:51820",
+        "10.0.0.2".parse().unwrap(),
+    );
+    let peers = HashSet::from([relay.clone(), peer.clone()]);
+    let keepalive_policy = KeepalivePolicy {
+        default: 25,
+        own: None,
+    };
+    let find = |peers: &HashSet<WgPeer>, public_key| {
+        peers
+            .iter()
+            .find(|x| x.public_key == public_key)
+            .cloned()
+            .unwrap()
+    };
+
+    // Without handshake information, nothing is relayed.
+    let mut relay_fallback = RelayFallback::new(Duration::ZERO);
+    let result = relay_fallback.apply(peers.clone(), keepalive_policy, None);
+
+    // As the timeout is zero, handshakes need to be in the future to count as recent.
+    let recently = SystemTime::now() + Duration::from_secs(60);
+    assert!(result.iter().all(|x| !x.relayed));
+
+    // There's no handshake with the peer, so its address is moved to the relay.
+    let handshakes = HashMap::from([(relay.public_key, recently)]);
+    let result = relay_fallback.apply(peers.clone(), keepalive_policy, Some(&handshakes));
+    assert!(find(&result, peer.public_key).relayed);
+    assert!(!find(&result, relay.public_key).relayed);
+    assert_eq!(
+        find(&result, relay.public_key).relayed_addresses,
+        vec![peer.address]
+    );
+
+    // Once we get a direct handshake, we switch back.
+    let handshakes = HashMap::from([(relay.public_key, recently), (peer.public_key, recently)]);
+    let result = relay_fallback.apply(peers.clone(), keepalive_policy, Some(&handshakes));
+    assert!(result
+        .iter()
+        .all(|x| !x.relayed && x.relayed_addresses.is_empty()));
+
+    // Without a keepalive, we can't tell whether a peer is reachable so we don't relay it.
+    let keepalive_policy = KeepalivePolicy {
+        default: 0,
+        own: None,
+    };
+    let handshakes = HashMap::from([(relay.public_key, recently)]);
+    let mut relay_fallback = RelayFallback::new(Duration::ZERO);
+    let result = relay_fallback.apply(peers, keepalive_policy, Some(&handshakes));
+    assert!(result.iter().all(|x| !x.relayed));
+}
+
+/// Peer configs published by older versions without any of the optional fields can still be read.
+#[rstest]
+fn peer_config_backward_compatible() -> Result<()> {
+    let peer = WgPeer::new(
+        Privkey::generate().pubkey(),
+        "203.0.113.1:51820",
+        "10.0.0.1".parse()?,
+    );
+    let old_json = serde_json::json!({
+        "public_key": peer.public_key,
+        "endpoint": peer.endpoint,
+        "address": peer.address,
+    });
+    let parsed: WgPeer = serde_json::from_value(old_json)?;
+    assert_eq!(parsed, peer);
+    assert!(parsed.metadata.is_empty());
+
+    // Nothing new is published if the optional fields aren't set.
+    assert_eq!(
+        serde_json::to_value(&peer)?.as_object().unwrap().len(),
+        3,
+        "{peer:?}"
+    );
+
+    Ok(())
+}
+
+/// Metadata is summarized for logs and the networkd config. Control characters published by other
+/// nodes can't inject lines into config files.
+#[rstest]
+#[case(NodeMetadata::default(), None)]
+#[case(NodeMetadata { hostname: Some("web-1".to_string()), ..Default::default() }, Some("web-1"))]
+#[case(
+    NodeMetadata {
+        hostname: Some("web-1".to_string()),
+        os: Some("Arch Linux".to_string()),
+        version: Some("0.4.5".to_string()),
+        started_at: Some("2026-10-18T12:00:00Z".to_string()),
+        labels: [("env".to_string(), "prod".to_string())].into(),
+    },
+    Some("web-1 (Arch Linux, wiresmith 0.4.5, env=prod)")
+)]
+#[case(
+    NodeMetadata {
+        hostname: Some("web-1\r".to_string()),
+        labels: [(
+            "note".to_string(),
+            "x\n[WireGuardPeer]\nPublicKey=abc\nAllowedIPs=0.0.0.0/0".to_string(),
+        )]
+        .into(),
+        ..Default::default()
+    },
+    Some("web-1  (note=x [WireGuardPeer] PublicKey=abc AllowedIPs=0.0.0.0/0)")
+)]
+fn metadata_description(#[case] metadata: NodeMetadata, #[case] expected: Option<&str>) {
+    assert_eq!(metadata.description().as_deref(), expected);
+}
+
+/// A newline in the metadata of a peer doesn't end up as a config line in the netdev file.
+#[rstest]
+#[tokio::test]
+async fn metadata_injection(tmpdir: TempDir) -> Result<()> {
+    let mut peer = WgPeer::new(
+        Privkey::generate().pubkey(),
+        "203.0.113.1:51820",
+        "10.0.0.2".parse()?,
+    );
+    peer.metadata.labels.insert(
+        "note".to_string(),
+        format!(
+            "x\n[WireGuardPeer]\nPublicKey={}\nAllowedIPs=0.0.0.0/0",
+            Privkey::generate().pubkey().to_base64()
+        ),
+    );
+    let config = NetworkdConfiguration::new(
+        Privkey::generate(),
+        "10.0.0.1".parse()?,
+        "10.0.0.0/24".parse()?,
+        51820,
+        "wg0",
+        LinkOptions::default(),
+        HashSet::from([peer.clone()]),
+    )?;
+    config
+        .write_config(
+            &tmpdir,
+            KeepalivePolicy {
+                default: 25,
+                own: None,
+            },
+        )
+        .await?;
+
+    let netdev = std::fs::read_to_string(tmpdir.join("wg0.netdev"))?;
+    assert_eq!(netdev.matches("[WireGuardPeer]").count(), 2, "{netdev}");
+    assert!(
+        !netdev.lines().any(|x| x == "AllowedIPs=0.0.0.0/0"),
+        "{netdev}"
+    );
+    let read = NetworkdConfiguration::from_config(&tmpdir, "wg0").await?;
+    assert_eq!(
+        read.peers.iter().map(|x| x.public_key).collect::<Vec<_>>(),
+        vec![peer.public_key]
+    );
+
+    Ok(())
+}
+
+/// Peer records are versioned. Unknown fields are ignored while records with a newer version or
+/// invalid records are rejected.
+#[rstest]
+#[case(None, None, true)]
+#[case(Some(PEER_RECORD_VERSION), None, true)]
+#[case(Some(PEER_RECORD_VERSION), Some(("future_field", "whatever")), true)]
+#[case(Some(PEER_RECORD_VERSION + 1), None, false)]
+#[case(Some(PEER_RECORD_VERSION), Some(("address", "not an address")), false)]
+fn peer_record_versions(
+    #[case] version: Option<u32>,
+    #[case] extra_field: Option<(&str, &str)>,
+    #[case] valid: bool,
+) -> Result<()> {
+    let peer = WgPeer::new(
+        Privkey::generate().pubkey(),
+        "203.0.113.1:51820",
+        "10.0.0.1".parse()?,
+    );
+    let mut record = serde_json::to_value(&peer)?;
+    if let Some(version) = version {
+        record["version"] = version.into();
+    }
+    if let Some((key, value)) = extra_field {
+        record[key] = value.into();
+    }
+
+    let parsed = PeerRecord::parse(&serde_json::to_vec(&record)?, None);
+    assert_eq!(parsed.is_ok(), valid, "{parsed:?}");
+    if valid {
+        assert_eq!(parsed?, peer);
+    }
+
+    // What we write ourselves can be read back.
+    let written = serde_json::to_vec(&PeerRecord::new(peer.clone(), None))?;
+    assert_eq!(PeerRecord::parse(&written, None)?, peer);
+
+    Ok(())
+}
+
+/// When the address of the endpoint interface changes, the new endpoint is published.
+#[rstest]
+#[tokio::test]
+async fn republishes_changed_endpoint(
+    #[future] consul: ConsulContainer,
+    tmpdir: TempDir,
+) -> Result<()> {
+    let consul = consul.await;
+
+    let wiresmith = WiresmithContainer::new(
+        "roaming",
+        "10.0.0.0/24",
+        &format!("wiresmith-{}", consul.http_port),
+        consul.http_port,
+        &[
+            "--update-period",
+            "1s",
+            "--endpoint-interface",
+            "eth0",
+            "--endpoint-family",
+            "v4",
+            "--endpoint-refresh-period",
+            "1s",
+        ],
+        &tmpdir,
+    )
+    .await;
+
+    wait_for_files(vec![
+        tmpdir.join("wg0.network").as_path(),
+        tmpdir.join("wg0.netdev").as_path(),
+    ])
+    .await;
+    sleep(Duration::from_secs(2)).await;
+
+    // Initially, the container has nothing but its private address.
+    let consul_peers = consul.client.get_peers().await?;
+    assert_eq!(consul_peers.len(), 1);
+    let endpoint = consul_peers.iter().next().unwrap().endpoint.clone();
+    assert!(endpoint.ends_with(":51820"));
+    assert_ne!(endpoint, "198.51.100.1:51820");
+
+    // The interface gets a public address which is preferred over the private one.
+    let ip_output = Command::new("podman")
+        .arg("exec")
+        .arg(&wiresmith.container_name)
+        .args(["ip", "address", "add", "198.51.100.1/32", "dev", "eth0"])
+        .output()
+        .await?;
+    ensure!(
+        ip_output.status.success(),
+        "Error adding address: {}",
+        String::from_utf8_lossy(&ip_output.stderr)
+    );
+
+    sleep(Duration::from_secs(3)).await;
+
+    let consul_peers = consul.client.get_peers().await?;
+    assert_eq!(consul_peers.len(), 1);
+    assert_eq!(
+        consul_peers.iter().next().unwrap().endpoint,
+        "198.51.100.1:51820"
+    );
+
+    Ok(())
+}
+
+/// A broken peer record in Consul is skipped rather than breaking everyone.
+#[rstest]
+#[tokio::test]
+async fn skips_invalid_peer_records(#[future] consul: ConsulContainer) -> Result<()> {
+    let consul = consul.await;
+
+    let peer = WgPeer::new(
+        Privkey::generate().pubkey(),
+        "203.0.113.1:51820",
+        "10.0.0.1".parse()?,
+    );
+    for (key, value) in [
+        (
+            "valid",
+            serde_json::to_vec(&PeerRecord::new(peer.clone(), None))?,
+        ),
+        ("garbage", b"not json".to_vec()),
+    ] {
+        consul
+            .client
+            .http_client
+            .put(consul.client.kv_api_base_url.join("peers/")?.join(key)?)
+            .body(value)
+            .send()
+            .await?
+            .error_for_status()?;
+    }
+
+    let consul_peers = consul.client.get_peers().await?;
+    assert_eq!(consul_peers, HashSet::from([peer]));
+
+    Ok(())
+}
+
+/// Secret key of the mesh authority used by tests
+const AUTHORITY: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+
+/// With a mesh authority, only records with an enrolment signed by it for the public key and
+/// address of the peer are accepted. Other fields of the record aren't covered by the enrolment.
+#[rstest]
+#[case::enrolled(Some(AUTHORITY), None, None, true)]
+#[case::not_enrolled(None, None, None, false)]
+#[case::other_authority(
+    Some("AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE="),
+    None,
+    None,
+    false
+)]
+#[case::other_address(Some(AUTHORITY), Some("10.0.0.2"), None, false)]
+#[case::other_public_key(Some(AUTHORITY), None, Some("public_key"), false)]
+#[case::tampered_signature(Some(AUTHORITY), None, Some("signature"), false)]
+#[case::changed_endpoint(Some(AUTHORITY), None, Some("endpoint"), true)]
+fn signed_peer_records(
+    #[case] secret_key: Option<&str>,
+    #[case] enrolled_address: Option<&str>,
+    #[case] tampered_field: Option<&str>,
+    #[case] valid: bool,
+) -> Result<()> {
+    let authority = AuthorityKey::from_base64(AUTHORITY)?.authority();
+
+    let mut peer = WgPeer::new(
+        Privkey::generate().pubkey(),
+        "203.0.113.1:51820",
+        "10.0.0.1".parse()?,
+    );
+    let enrolment = match secret_key {
+        Some(secret_key) => Some(AuthorityKey::from_base64(secret_key)?.enrol(
+            peer.public_key,
+            enrolled_address.unwrap_or("10.0.0.1").parse()?,
+        )?),
+        None => None,
+    };
+    let mut record = serde_json::to_value(PeerRecord::new(peer.clone(), enrolment))?;
+    match tampered_field {
+        Some("public_key") => {
+            peer.public_key = Privkey::generate().pubkey();
+            record["public_key"] = serde_json::to_value(peer.public_key)?;
+        }
+        Some("signature") => {
+            record["enrolment"]["signature"] = BASE64_STANDARD.encode([0; 64]).into();
+        }
+        Some("endpoint") => {
+            peer.endpoint = "198.51.100.1:51820".to_string();
+            record["endpoint"] = peer.endpoint.clone().into();
+        }
+        _ => {}
+    }
+
+    let parsed = PeerRecord::parse(&serde_json::to_vec(&record)?, Some(&authority));
+    assert_eq!(parsed.is_ok(), valid, "{parsed:?}");
+    if valid {
+        assert_eq!(parsed?, peer);
+    }
+
+    // Without an authority, enrolments are ignored.
+    assert!(PeerRecord::parse(&serde_json::to_vec(&record)?, None).is_ok());
+
+    Ok(())
+}
+
+/// Nodes only get the public key of the authority which can't sign enrolments.
+#[rstest]
+fn authority_public_key() -> Result<()> {
+    let authority_key = AuthorityKey::from_base64(AUTHORITY)?;
+    let authority = MeshAuthority::from_base64(&authority_key.authority().to_base64())?;
+    assert_eq!(authority, authority_key.authority());
+    assert_ne!(authority.to_base64(), AUTHORITY);
+
+    let public_key = Privkey::generate().pubkey();
+    let enrolment = authority_key.enrol(public_key, "10.0.0.1".parse()?)?;
+    authority.verify_enrolment(&enrolment, public_key, "10.0.0.1".parse()?)?;
+    assert!(authority
+        .verify_enrolment(&enrolment, public_key, "10.0.0.2".parse()?)
+        .is_err());
+
+    let json = serde_json::to_string(&enrolment)?;
+    assert_eq!(serde_json::from_str::<Enrolment>(&json)?, enrolment);
+
+    Ok(())
+}
+
+/// Join tokens are stored under the hash of their secret and can only be claimed by a single node
+/// before they expire. Claiming a token again with the same node succeeds.
+#[rstest]
+#[tokio::test]
+async fn join_tokens(#[future] consul: ConsulContainer) -> Result<()> {
+    let consul = consul.await;
+    let node = Privkey::generate().pubkey();
+    let other_node = Privkey::generate().pubkey();
+
+    let secret = enrolment::generate_secret();
+    consul
+        .client
+        .create_join_token(
+            &secret,
+            &JoinToken::new(
+                &secret,
+                Duration::from_secs(3600),
+                Some("10.0.0.7".parse()?),
+            ),
+        )
+        .await?;
+    assert_ne!(enrolment::token_id(&secret), secret);
+    assert_eq!(
+        consul.client.get_enrolments().await?.state(&node),
+        EnrolmentState::Pending
+    );
+
+    let token = consul.client.claim_join_token(&secret, node).await?;
+    assert_eq!(token.address, Some("10.0.0.7".parse()?));
+    consul.client.claim_join_token(&secret, node).await?;
+    assert!(consul
+        .client
+        .claim_join_token(&secret, other_node)
+        .await
+        .is_err());
+    assert!(consul
+        .client
+        .claim_join_token("unknown", other_node)
+        .await
+        .is_err());
+
+    let expired = enrolment::generate_secret();
+    consul
+        .client
+        .create_join_token(&expired, &JoinToken::new(&expired, Duration::ZERO, None))
+        .await?;
+    sleep(Duration::from_secs(1)).await;
+    assert!(consul
+        .client
+        .claim_join_token(&expired, other_node)
+        .await
+        .is_err());
+
+    let enrolments = consul.client.get_enrolments().await?;
+    assert_eq!(enrolments.state(&node), EnrolmentState::Enrolled);
+    assert_eq!(enrolments.state(&other_node), EnrolmentState::Pending);
+
+    Ok(())
+}
+
+#[rstest]
+#[case(Duration::from_secs(3600), false)]
+#[case(Duration::ZERO, true)]
+fn join_token_expiry(#[case] ttl: Duration, #[case] expired: bool) -> Result<()> {
+    let secret = enrolment::generate_secret();
+    let token = JoinToken::new(&secret, ttl, None);
+    // Tokens are stored with second precision.
+    std::thread::sleep(Duration::from_secs(1));
+    assert_eq!(token.is_expired()?, expired);
+
+    // The token survives a round trip through Consul without the optional fields.
+    let json = serde_json::to_value(&token)?;
+    assert_eq!(
+        json,
+        serde_json::json!({ "expires_at": token.expires_at, "claim_key": token.claim_key })
+    );
+    assert_eq!(serde_json::from_value::<JoinToken>(json)?, token);
+
+    Ok(())
+}
+
+/// Only claims signed with the key derived from the secret of an existing token count, as long as
+/// the token wasn't expired and was only claimed once. With a mesh authority, the token also needs
+/// to be signed by it.
+#[rstest]
+#[case::claimed(false, false, None, true)]
+#[case::unknown_token(false, false, Some("unknown"), false)]
+#[case::wrong_secret(false, false, Some("wrong"), false)]
+#[case::expired(false, false, Some("expired"), false)]
+#[case::claimed_twice(false, false, Some("twice"), false)]
+#[case::signed(true, true, None, true)]
+#[case::not_signed(true, false, None, false)]
+fn join_token_claims(
+    #[case] with_authority: bool,
+    #[case] signed: bool,
+    #[case] tamper: Option<&str>,
+    #[case] enrolled: bool,
+) -> Result<()> {
+    let authority_key = AuthorityKey::from_base64(AUTHORITY)?;
+    let node = Privkey::generate().pubkey();
+    let secret = enrolment::generate_secret();
+
+    let mut token = JoinToken::new(&secret, Duration::from_secs(3600), None);
+    if signed {
+        token = token.sign(&authority_key)?;
+    }
+    let mut claim = JoinClaim::new(&secret, node)?;
+    let mut claims = vec![];
+    match tamper {
+        Some("unknown") => token = JoinToken::new("other", Duration::from_secs(3600), None),
+        Some("wrong") => {
+            claim = JoinClaim {
+                claim_key: token.claim_key.clone(),
+                ..JoinClaim::new("other", node)?
+            }
+        }
+        Some("expired") => token.expires_at = "2020-01-01T00:00:00Z".to_string(),
+        Some("twice") => claims.push(JoinClaim::new(&secret, Privkey::generate().pubkey())?),
+        _ => {}
+    }
+    claims.push(claim);
+
+    let authority = authority_key.authority();
+    let enrolments =
+        Enrolments::verify(&[token], &claims, &[], with_authority.then_some(&authority));
+    assert_eq!(
+        enrolments.state(&node),
+        if enrolled {
+            EnrolmentState::Enrolled
+        } else {
+            EnrolmentState::Pending
+        }
+    );
+
+    Ok(())
+}
+
+/// With a mesh authority, only approvals signed by it count.
+#[rstest]
+#[case::unsigned(false, false, EnrolmentState::Enrolled)]
+#[case::signed(true, true, EnrolmentState::Enrolled)]
+#[case::not_signed(true, false, EnrolmentState::Pending)]
+fn approval_signatures(
+    #[case] with_authority: bool,
+    #[case] signed: bool,
+    #[case] expected: EnrolmentState,
+) -> Result<()> {
+    let authority_key = AuthorityKey::from_base64(AUTHORITY)?;
+    let node = Privkey::generate().pubkey();
+    let mut approval = Approval::new(node, true);
+    if signed {
+        approval = approval.sign(&authority_key)?;
+    }
+
+    let authority = authority_key.authority();
+    let enrolments =
+        Enrolments::verify(&[], &[], &[approval], with_authority.then_some(&authority));
+    assert_eq!(enrolments.state(&node), expected);
+
+    Ok(())
+}
+
+/// Operator decisions win over claimed join tokens so that any node can be revoked.
+#[rstest]
+#[case::unknown(false, None, EnrolmentState::Pending)]
+#[case::claimed(true, None, EnrolmentState::Enrolled)]
+#[case::approved(false, Some(true), EnrolmentState::Enrolled)]
+#[case::rejected(false, Some(false), EnrolmentState::Rejected)]
+#[case::claimed_and_rejected(true, Some(false), EnrolmentState::Rejected)]
+fn enrolment_state(
+    #[case] claimed: bool,
+    #[case] approved: Option<bool>,
+    #[case] expected: EnrolmentState,
+) {
+    let node = Privkey::generate().pubkey();
+    let mut enrolments = Enrolments::default();
+    if claimed {
+        enrolments.claimed.insert(node);
+    }
+    if let Some(approved) = approved {
+        enrolments.approvals.insert(node, approved);
+    }
+    assert_eq!(enrolments.state(&node), expected);
+}
+
+/// Pending peers are kept apart from the active ones until an operator approves them.
+#[rstest]
+#[tokio::test]
+async fn approval_queue(#[future] consul: ConsulContainer) -> Result<()> {
+    let consul = consul.await;
+    let peer = WgPeer::new(
+        Privkey::generate().pubkey(),
+        "203.0.113.1:51820",
+        "10.0.0.1".parse()?,
+    );
+
+    let token = CancellationToken::new();
+    let session = consul
+        .client
+        .create_session(peer.public_key, PeerState::Pending, None, token.clone())
+        .await?;
+    session.update_config(&peer).await?;
+    assert!(consul.client.get_peers().await?.is_empty());
+    assert_eq!(
+        consul.client.get_pending_peers().await?,
+        HashSet::from([peer.clone()])
+    );
+    assert_eq!(
+        consul
+            .client
+            .get_enrolments()
+            .await?
+            .state(&peer.public_key),
+        EnrolmentState::Pending
+    );
+
+    consul.client.decide_approval(peer.public_key, true).await?;
+    assert_eq!(
+        consul
+            .client
+            .get_enrolments()
+            .await?
+            .state(&peer.public_key),
+        EnrolmentState::Enrolled
+    );
+
+    consul
+        .client
+        .decide_approval(peer.public_key, false)
+        .await?;
+    assert_eq!(
+        consul
+            .client
+            .get_enrolments()
+            .await?
+            .state(&peer.public_key),
+        EnrolmentState::Rejected
+    );
+
+    session.cancel().await?;
+
+    Ok(())
+}
+
+/// Static peers are read from a file and replace discovered peers with the same public key. All
+/// allowed IPs after the first one are routed to the peer as well.
+#[rstest]
+#[tokio::test]
+async fn static_peers(tmpdir: TempDir) -> Result<()> {
+    let appliance = Privkey::generate().pubkey();
+    let static_peers_file = tmpdir.join("static-peers.json");
+    std::fs::write(
+        &static_peers_file,
+        serde_json::to_vec(&[StaticPeer {
+            public_key: appliance,
+            endpoint: "198.51.100.1:51820".to_string(),
+            allowed_ips: vec!["10.0.0.200/32".parse()?, "192.168.1.0/24".parse()?],
+            keepalive: Some(0),
+        }])?,
+    )?;
+    let static_peers = static_peers::from_file(&static_peers_file).await?;
+
+    let discovered = WgPeer::new(
+        Privkey::generate().pubkey(),
+        "203.0.113.1:51820",
+        "10.0.0.1".parse()?,
+    );
+    let outdated = WgPeer::new(appliance, "203.0.113.2:51820", "10.0.0.2".parse()?);
+    let peers = static_peers::merge(HashSet::from([discovered.clone(), outdated]), &static_peers);
+
+    let appliance = peers
+        .iter()
+        .find(|x| x.public_key == appliance)
+        .expect("Static peer is missing");
+    assert_eq!(appliance.endpoint, "198.51.100.1:51820");
+    assert_eq!(appliance.address, "10.0.0.200/32".parse()?);
+    assert_eq!(appliance.relayed_addresses, vec!["192.168.1.0/24".parse()?]);
+    assert_eq!(appliance.keepalive, Some(0));
+    assert_eq!(peers.len(), 2);
+    assert!(peers.contains(&discovered));
+
+    // Static peers need an address.
+    std::fs::write(
+        &static_peers_file,
+        format!(
+            r#"[{{"public_key": "{}", "endpoint": "198.51.100.1:51820", "allowed_ips": []}}]"#,
+            appliance.public_key
+        ),
+    )?;
+    assert!(static_peers::from_file(&static_peers_file).await.is_err());
+
+    Ok(())
+}
+
+/// External peers are stored without a session so that they stay until they're removed. Configs
+/// of running nodes can't be removed that way.
+#[rstest]
+#[tokio::test]
+async fn external_peers(#[future] consul: ConsulContainer) -> Result<()> {
+    let consul = consul.await;
+    let external = WgPeer::new(
+        Privkey::generate().pubkey(),
+        "198.51.100.1:51820",
+        "10.0.0.200".parse()?,
+    );
+
+    consul.client.add_external_peer(&external, None).await?;
+    assert!(consul
+        .client
+        .add_external_peer(&external, None)
+        .await
+        .is_err());
+    assert_eq!(
+        consul.client.get_peers().await?,
+        HashSet::from([external.clone()])
+    );
+    assert_eq!(
+        consul
+            .client
+            .get_enrolments()
+            .await?
+            .state(&external.public_key),
+        EnrolmentState::Enrolled
+    );
+
+    let node = WgPeer::new(
+        Privkey::generate().pubkey(),
+        "203.0.113.1:51820",
+        "10.0.0.1".parse()?,
+    );
+    let session = consul
+        .client
+        .create_session(
+            node.public_key,
+            PeerState::Active,
+            None,
+            CancellationToken::new(),
+        )
+        .await?;
+    session.update_config(&node).await?;
+    assert!(consul
+        .client
+        .remove_external_peer(node.public_key)
+        .await
+        .is_err());
+
+    consul
+        .client
+        .remove_external_peer(external.public_key)
+        .await?;
+    assert!(consul
+        .client
+        .remove_external_peer(external.public_key)
+        .await
+        .is_err());
+    assert_eq!(consul.client.get_peers().await?, HashSet::from([node]));
+
+    session.cancel().await?;
+
+    Ok(())
+}
+
+/// A device exported for a hub is reached by the other nodes via that hub.
+#[rstest]
+#[tokio::test]
+async fn roaming_device_behind_hub(
+    #[future] consul: ConsulContainer,
+    #[from(tmpdir)] tmpdir_a: TempDir,
+    #[from(tmpdir)] tmpdir_b: TempDir,
+) -> Result<()> {
+    let consul = consul.await;
+
+    let _wiresmith_a = WiresmithContainer::new(
+        "a",
+        "10.0.0.0/24",
+        &format!("wiresmith-{}", consul.http_port),
+        consul.http_port,
+        &["--update-period", "1s"],
+        &tmpdir_a,
+    )
+    .await;
+    wait_for_files(vec![
+        tmpdir_a.join("wg0.network").as_path(),
+        tmpdir_a.join("wg0.netdev").as_path(),
+    ])
+    .await;
+    let _wiresmith_b = WiresmithContainer::new(
+        "b",
+        "10.0.0.0/24",
+        &format!("wiresmith-{}", consul.http_port),
+        consul.http_port,
+        &["--update-period", "1s"],
+        &tmpdir_b,
+    )
+    .await;
+    wait_for_files(vec![
+        tmpdir_b.join("wg0.network").as_path(),
+        tmpdir_b.join("wg0.netdev").as_path(),
+    ])
+    .await;
+    sleep(Duration::from_secs(2)).await;
+
+    let hub = NetworkdConfiguration::from_config(&tmpdir_a, "wg0").await?;
+    let client_config = ClientConfig::new(
+        "alice",
+        Privkey::generate(),
+        "10.0.0.100/24".parse()?,
+        &consul.client.get_peers().await?,
+        Some(hub.public_key),
+        25,
+    )?;
+    let device = client_config.wg_peer();
+    consul.client.add_external_peer(&device, None).await?;
+    sleep(Duration::from_secs(2)).await;
+
+    // The hub peers with the device directly.
+    let networkd_config_a = NetworkdConfiguration::from_config(&tmpdir_a, "wg0").await?;
+    assert!(networkd_config_a
+        .peers
+        .iter()
+        .any(|x| x.public_key == device.public_key && x.address == device.address));
+
+    // The other node routes the device via the hub.
+    let networkd_config_b = NetworkdConfiguration::from_config(&tmpdir_b, "wg0").await?;
+    assert!(!networkd_config_b
+        .peers
+        .iter()
+        .any(|x| x.public_key == device.public_key));
+    let hub_peer = networkd_config_b
+        .peers
+        .iter()
+        .find(|x| x.public_key == hub.public_key)
+        .expect("Hub isn't a peer");
+    assert_eq!(hub_peer.relayed_addresses, vec![device.address]);
+
+    Ok(())
+}
+
+/// Roaming devices peer with every node that has an endpoint, or only with a hub which routes the
+/// whole network for them.
+#[rstest]
+#[case::mesh(false)]
+#[case::hub(true)]
+fn client_config(#[case] with_hub: bool) -> Result<()> {
+    let mut hub = WgPeer::new(
+        Privkey::generate().pubkey(),
+        "203.0.113.1:51820",
+        "10.0.0.1".parse()?,
+    );
+    hub.metadata.hostname = Some("hub".to_string());
+    let mut node = WgPeer::new(
+        Privkey::generate().pubkey(),
+        "203.0.113.2:51820",
+        "10.0.0.2".parse()?,
+    );
+    node.metadata.hostname = Some("node\n[Peer]".to_string());
+    let roaming = WgPeer::new(Privkey::generate().pubkey(), "", "10.0.0.3".parse()?);
+    let peers = HashSet::from([hub.clone(), node.clone(), roaming.clone()]);
+
+    let client_config = ClientConfig::new(
+        "alice",
+        Privkey::generate(),
+        "10.0.0.4/24".parse()?,
+        &peers,
+        with_hub.then_some(hub.public_key),
+        25,
+    )?;
+    let wg_peer = client_config.wg_peer();
+    assert_eq!(wg_peer.public_key, client_config.private_key.pubkey());
+    assert_eq!(wg_peer.endpoint, "");
+    assert_eq!(wg_peer.address, "10.0.0.4/32".parse()?);
+    assert_eq!(wg_peer.metadata.hostname.as_deref(), Some("alice"));
+    assert_eq!(wg_peer.hub, with_hub.then_some(hub.public_key));
+
+    // Without a hub, every node peers with the device directly. With one, the other nodes route
+    // the device via the hub instead.
+    let mesh = topology::route_via_hubs(
+        node.public_key,
+        HashSet::from([hub.clone(), wg_peer.clone()]),
+    );
+    let routes = mesh
+        .iter()
+        .map(|x| (x.public_key, x.relayed_addresses.clone()))
+        .collect::<HashMap<_, _>>();
+    if with_hub {
+        assert_eq!(
+            routes,
+            HashMap::from([(hub.public_key, vec!["10.0.0.4/32".parse()?])])
+        );
+    } else {
+        assert_eq!(
+            routes,
+            HashMap::from([(hub.public_key, vec![]), (wg_peer.public_key, vec![])])
+        );
+    }
+    let hub_peers = topology::route_via_hubs(hub.public_key, HashSet::from([wg_peer.clone()]));
+    assert_eq!(hub_peers, HashSet::from([wg_peer.clone()]));
+
+    let hub_section = format!(
+        "# hub\n[Peer]\nPublicKey = {}\nEndpoint = 203.0.113.1:51820\nAllowedIPs = {}\nPersistentKeepalive = 25\n",
+        hub.public_key.to_base64(),
+        if with_hub { "10.0.0.0/24" } else { "10.0.0.1/32" }
+    );
+    let node_section = format!(
+        "# node [Peer]\n[Peer]\nPublicKey = {}\nEndpoint = 203.0.113.2:51820\nAllowedIPs = 10.0.0.2/32\nPersistentKeepalive = 25\n",
+        node.public_key.to_base64(),
+    );
+    let mut expected = format!(
+        "# alice\n[Interface]\nPrivateKey = {}\nAddress = 10.0.0.4/24\n\n{hub_section}",
+        client_config.private_key.to_base64()
+    );
+    if !with_hub {
+        expected.push_str(&format!("\n{node_section}"));
+    }
+    assert_eq!(client_config.to_wg_quick(), expected);
+    assert!(!client_config.to_qr_code()?.is_empty());
+
+    // The hub needs to be part of the mesh.
+    assert!(ClientConfig::new(
+        "alice",
+        Privkey::generate(),
+        "10.0.0.4/24".parse()?,
+        &peers,
+        Some(Privkey::generate().pubkey()),
+        25
+    )
+    .is_err());
+
+    // The hub needs an endpoint the device can connect to.
+    assert!(ClientConfig::new(
+        "alice",
+        Privkey::generate(),
+        "10.0.0.4/24".parse()?,
+        &peers,
+        Some(roaming.public_key),
+        25
+    )
+    .is_err());
+
+    Ok(())
+}
+
+/// Addresses are handed out according to the strategy, skipping occupied addresses and the ones
+/// that are still in quarantine.
+#[rstest]
+#[case::lowest_free(AllocationStrategy::LowestFree, "10.0.0.3")]
+#[case::least_recently_used(AllocationStrategy::LeastRecentlyUsed, "10.0.0.5")]
+fn address_allocation(
+    #[case] strategy: AllocationStrategy,
+    #[case] expected: IpAddr,
+) -> Result<()> {
+    let network = "10.0.0.0/29".parse()?;
+    let public_key = Privkey::generate().pubkey();
+    let used_at = |address: &str, ago: u64| -> Result<AddressUsage> {
+        Ok(AddressUsage {
+            public_key: Privkey::generate().pubkey(),
+            address: address.parse()?,
+            last_seen: humantime::format_rfc3339_seconds(
+                SystemTime::now() - Duration::from_secs(ago),
+            )
+            .to_string(),
+        })
+    };
+
+    // 10.0.0.2 is in quarantine, 10.0.0.3 and 10.0.0.4 were used before, 10.0.0.5 and 10.0.0.6
+    // were never used.
+    let mut allocator = AddressAllocator {
+        strategy,
+        quarantine: Duration::from_secs(3600),
+        occupied: HashSet::from(["10.0.0.1".parse()?]),
+        usage: vec![
+            used_at("10.0.0.2", 60)?,
+            used_at("10.0.0.3", 7200)?,
+            used_at("10.0.0.4", 9000)?,
+        ],
+        reserved: vec![],
+    };
+    assert_eq!(
+        allocator.allocate(&network, &public_key, None),
+        Some(expected)
+    );
+
+    // Once all addresses were used, the one that wasn't used for the longest time wins.
+    allocator
+        .occupied
+        .extend(["10.0.0.5".parse::<IpAddr>()?, "10.0.0.6".parse()?]);
+    let expected = match strategy {
+        AllocationStrategy::LeastRecentlyUsed => "10.0.0.4",
+        _ => "10.0.0.3",
+    };
+    assert_eq!(
+        allocator.allocate(&network, &public_key, None),
+        Some(expected.parse()?)
+    );
+
+    allocator
+        .occupied
+        .extend(["10.0.0.3".parse::<IpAddr>()?, "10.0.0.4".parse()?]);
+    assert_eq!(allocator.allocate(&network, &public_key, None), None);
+
+    Ok(())
+}
+
+/// Hash based strategies are deterministic and move on to the next free address on collisions.
+#[rstest]
+#[case::public_key(AllocationStrategy::PublicKeyHash)]
+#[case::hostname(AllocationStrategy::HostnameHash)]
+#[case::random(AllocationStrategy::Random)]
+fn address_allocation_offset(#[case] strategy: AllocationStrategy) -> Result<()> {
+    let network: IpNet = "fc00::/64".parse()?;
+    let public_key = Privkey::generate().pubkey();
+    let mut allocator = AddressAllocator {
+        strategy,
+        quarantine: Duration::ZERO,
+        occupied: HashSet::new(),
+        usage: vec![],
+        reserved: vec![],
+    };
+
+    let address = allocator
+        .allocate(&network, &public_key, Some("web-1"))
+        .expect("No address allocated");
+    assert!(network.contains(&address));
+
+    if strategy == AllocationStrategy::Random {
+        return Ok(());
+    }
+    assert_eq!(
+        allocator.allocate(&network, &public_key, Some("web-1")),
+        Some(address)
+    );
+    if strategy == AllocationStrategy::HostnameHash {
+        assert_eq!(
+            allocator.allocate(&network, &Privkey::generate().pubkey(), Some("web-1")),
+            Some(address)
+        );
+    }
+
+    allocator.occupied.insert(address);
+    let next = allocator
+        .allocate(&network, &public_key, Some("web-1"))
+        .expect("No address allocated");
+    assert_ne!(next, address);
+    assert!(network.contains(&next));
+
+    Ok(())
+}
+
+/// Allocation doesn't enumerate large networks, IPv6 networks derive addresses from the public key
+/// by default and never hand out the Subnet-Router anycast address.
+#[rstest]
+fn address_allocation_large_networks() -> Result<()> {
+    let v4: IpNet = "10.0.0.0/16".parse()?;
+    let v6: IpNet = "fc00::/64".parse()?;
+    assert_eq!(
+        AllocationStrategy::for_network(&v4),
+        AllocationStrategy::LowestFree
+    );
+    assert_eq!(
+        AllocationStrategy::for_network(&v6),
+        AllocationStrategy::PublicKeyHash
+    );
+
+    let public_key = Privkey::generate().pubkey();
+    let mut allocator = AddressAllocator {
+        strategy: AllocationStrategy::LowestFree,
+        quarantine: Duration::ZERO,
+        occupied: HashSet::new(),
+        usage: vec![],
+        reserved: vec![],
+    };
+    assert_eq!(
+        allocator.allocate(&v6, &public_key, None),
+        Some("fc00::1".parse()?)
+    );
+    assert_eq!(
+        allocator.allocate(&"fc00::/127".parse()?, &public_key, None),
+        Some("fc00::".parse()?)
+    );
+
+    // A nearly full /16 only leaves its last address.
+    allocator.occupied = v4.hosts().take(65533).collect();
+    assert_eq!(
+        allocator.allocate(&v4, &public_key, None),
+        Some("10.0.255.254".parse()?)
+    );
+
+    // Lots of peers in a /64 don't matter as long as the derived address is free.
+    allocator.strategy = AllocationStrategy::for_network(&v6);
+    allocator.occupied = v6.hosts().take(65536).collect();
+    let address = allocator
+        .allocate(&v6, &public_key, None)
+        .expect("No address allocated");
+    assert!(v6.contains(&address));
+    assert!(!allocator.occupied.contains(&address));
+    assert_eq!(allocator.allocate(&v6, &public_key, None), Some(address));
+
+    Ok(())
+}
+
+/// Reserved ranges can be given as networks or address ranges and are skipped by the allocator.
+#[rstest]
+fn address_reservations() -> Result<()> {
+    let range: AddressRange = "10.0.0.1-10.0.0.10".parse()?;
+    assert_eq!(range.to_string(), "10.0.0.1-10.0.0.10");
+    assert!(range.contains(&"10.0.0.10".parse()?));
+    assert!(!range.contains(&"10.0.0.11".parse()?));
+    assert!(!range.contains(&"::a".parse()?));
+
+    let network: AddressRange = "10.0.1.0/24".parse()?;
+    assert_eq!(network.to_string(), "10.0.1.0-10.0.1.255");
+    assert_eq!("10.0.0.7".parse::<AddressRange>()?.to_string(), "10.0.0.7");
+    assert!("10.0.0.10-10.0.0.1".parse::<AddressRange>().is_err());
+    assert!("10.0.0.1-::1".parse::<AddressRange>().is_err());
+
+    let reservations: Vec<AddressRange> =
+        serde_json::from_str(r#"["10.0.0.1-10.0.0.10", "10.0.1.0/24"]"#)?;
+    assert_eq!(reservations, vec![range, network]);
+    assert_eq!(
+        serde_json::to_string(&reservations)?,
+        r#"["10.0.0.1-10.0.0.10","10.0.1.0-10.0.1.255"]"#
+    );
+
+    let allocator = AddressAllocator {
+        strategy: AllocationStrategy::LowestFree,
+        quarantine: Duration::ZERO,
+        occupied: HashSet::from(["10.0.0.11".parse()?]),
+        usage: vec![],
+        reserved: reservations,
+    };
+    let public_key = Privkey::generate().pubkey();
+    assert_eq!(
+        allocator.allocate(&"10.0.0.0/24".parse()?, &public_key, None),
+        Some("10.0.0.12".parse()?)
+    );
+    assert_eq!(
+        allocator.allocate(&"10.0.0.0/28".parse()?, &public_key, None),
+        Some("10.0.0.12".parse()?)
+    );
+    assert_eq!(
+        allocator.allocate(&"10.0.1.0/24".parse()?, &public_key, None),
+        None
+    );
+
+    // Large reservations are skipped at once.
+    let network: IpNet = "fd00::/64".parse()?;
+    let allocator = AddressAllocator {
+        strategy: AllocationStrategy::LowestFree,
+        quarantine: Duration::ZERO,
+        occupied: HashSet::new(),
+        usage: vec![],
+        reserved: vec!["fd00::/72".parse()?],
+    };
+    assert_eq!(
+        allocator.allocate(&network, &public_key, None),
+        Some("fd00::100:0:0:0".parse()?)
+    );
+    let allocator = AddressAllocator {
+        strategy: AllocationStrategy::PublicKeyHash,
+        reserved: vec!["fd00::-fd00::ffff:ffff:ffff:fffe".parse()?],
+        ..allocator
+    };
+    assert_eq!(
+        allocator.allocate(&network, &public_key, None),
+        Some("fd00::ffff:ffff:ffff:ffff".parse()?)
+    );
+    let allocator = AddressAllocator {
+        reserved: vec!["fd00::/64".parse()?],
+        ..allocator
+    };
+    assert_eq!(allocator.allocate(&network, &public_key, None), None);
+
+    Ok(())
+}
+
+/// Address leases are stored per identity so that a reinstalled node with a new key can find the
+/// address it had before.
+#[rstest]
+#[tokio::test]
+async fn address_leases(#[future] consul: ConsulContainer) -> Result<()> {
+    let consul = consul.await;
+    assert_eq!(consul.client.get_lease("web-1").await?, None);
+
+    let lease = AddressLease::new("web-1", "10.0.0.7".parse()?, Privkey::generate().pubkey());
+    consul.client.put_lease(&lease).await?;
+    assert_eq!(consul.client.get_lease("web-1").await?, Some(lease));
+
+    let reinstalled = AddressLease::new("web-1", "10.0.0.7".parse()?, Privkey::generate().pubkey());
+    consul.client.put_lease(&reinstalled).await?;
+    assert_eq!(consul.client.get_lease("web-1").await?, Some(reinstalled));
+    assert_eq!(consul.client.get_lease("web-2").await?, None);
+
+    Ok(())
+}
+
+/// The hostname identity requires a hostname.
+#[rstest]
+fn lease_identity() -> Result<()> {
+    assert_eq!(
+        LeaseIdentity::Hostname.resolve(Some("web-1"))?,
+        "web-1".to_string()
+    );
+    assert!(LeaseIdentity::Hostname.resolve(None).is_err());
+    Ok(())
+}
+
+/// A config loaded from disk is moved to a changed network and port, keeping its key.
+#[rstest]
+fn migrate_config() -> Result<()> {
+    let private_key = Privkey::generate();
+    let mut config = NetworkdConfiguration::new(
+        private_key,
+        "10.0.0.7".parse()?,
+        "10.0.0.0/24".parse()?,
+        51820,
+        "wg0",
+        LinkOptions::default(),
+        HashSet::new(),
+    )?;
+
+    assert!(!config.migrate("10.0.0.7".parse()?, "10.0.0.0/24".parse()?, 51820)?);
+    assert_eq!(config.wg_address, "10.0.0.7/24".parse::<IpNet>()?);
+
+    assert!(config.migrate("10.0.0.7".parse()?, "10.0.0.0/16".parse()?, 51820)?);
+    assert_eq!(config.wg_address, "10.0.0.7/16".parse::<IpNet>()?);
+
+    assert!(config.migrate("10.1.0.1".parse()?, "10.1.0.0/16".parse()?, 51821)?);
+    assert_eq!(config.wg_address, "10.1.0.1/16".parse::<IpNet>()?);
+    assert_eq!(config.wg_port, 51821);
+    assert_eq!(config.public_key, private_key.pubkey());
+
+    Ok(())
+}
+
+/// A config on disk that was moved to another network or port while we were running is moved back
+/// to the address and port we published.
+#[rstest]
+#[tokio::test]
+async fn reconcile_config(tmpdir: TempDir) -> Result<()> {
+    let private_key = Privkey::generate();
+    let keepalive_policy = KeepalivePolicy {
+        default: 25,
+        own: None,
+    };
+    let config = NetworkdConfiguration::new(
+        private_key,
+        "10.0.0.7".parse()?,
+        "10.0.0.0/24".parse()?,
+        51820,
+        "wg0",
+        LinkOptions::default(),
+        HashSet::new(),
+    )?;
+    config.write_config(&tmpdir, keepalive_policy).await?;
+
+    // Nothing to do as long as the config matches.
+    let reconciled =
+        NetworkdConfiguration::reconcile(&tmpdir, "wg0", config.wg_address, config.wg_port).await?;
+    assert!(!reconciled.write_config(&tmpdir, keepalive_policy).await?);
+
+    let network_file = tmpdir.join("wg0.network");
+    let network = std::fs::read_to_string(&network_file)?
+        .replace("Address=10.0.0.7/24", "Address=192.168.0.7/16");
+    std::fs::write(&network_file, network)?;
+    let netdev_file = tmpdir.join("wg0.netdev");
+    let netdev =
+        std::fs::read_to_string(&netdev_file)?.replace("ListenPort=51820", "ListenPort=51821");
+    std::fs::write(&netdev_file, netdev)?;
+
+    let reconciled =
+        NetworkdConfiguration::reconcile(&tmpdir, "wg0", config.wg_address, config.wg_port).await?;
+    assert_eq!(reconciled.wg_address, "10.0.0.7/24".parse::<IpNet>()?);
+    assert_eq!(reconciled.wg_port, 51820);
+    assert!(reconciled.write_config(&tmpdir, keepalive_policy).await?);
+
+    let read = NetworkdConfiguration::from_config(&tmpdir, "wg0").await?;
+    assert_eq!(read.wg_address, "10.0.0.7/24".parse::<IpNet>()?);
+    assert_eq!(read.wg_port, 51820);
+    assert_eq!(read.public_key, private_key.pubkey());
+
+    Ok(())
+}
+
+/// Interface options in a format we don't know are left as they are instead of making the whole
+/// config unreadable, which would otherwise replace the key and address of the node.
+#[rstest]
+#[tokio::test]
+async fn unknown_link_option_values(tmpdir: TempDir) -> Result<()> {
+    let private_key = Privkey::generate();
+    let keepalive_policy = KeepalivePolicy {
+        default: 25,
+        own: None,
+    };
+    let config = NetworkdConfiguration::new(
+        private_key,
+        "10.0.0.7".parse()?,
+        "10.0.0.0/24".parse()?,
+        51820,
+        "wg0",
+        LinkOptions {
+            route_metric: Some(100),
+            ..Default::default()
+        },
+        HashSet::new(),
+    )?;
+    config.write_config(&tmpdir, keepalive_policy).await?;
+
+    let netdev_file = tmpdir.join("wg0.netdev");
+    let netdev = std::fs::read_to_string(&netdev_file)?
+        .replace("MTUBytes=1280", "MTUBytes=1.5K")
+        .replace("RouteMetric=100", "RouteMetric=100\nFirewallMark=0x10");
+    std::fs::write(&netdev_file, netdev)?;
+
+    let read = NetworkdConfiguration::from_config(&tmpdir, "wg0").await?;
+    assert_eq!(read.public_key, private_key.pubkey());
+    assert_eq!(read.wg_address, "10.0.0.7/24".parse::<IpNet>()?);
+    assert_eq!(
+        read.link_options,
+        LinkOptions {
+            route_metric: Some(100),
+            ..Default::default()
+        }
+    );
+
+    assert!(!read.write_config(&tmpdir, keepalive_policy).await?);
+    let netdev_ini = ini::Ini::load_from_file(&netdev_file)?;
+    assert_eq!(
+        netdev_ini.get_from(Some("NetDev"), "MTUBytes"),
+        Some("1.5K")
+    );
+    assert_eq!(
+        netdev_ini.get_from(Some("WireGuard"), "FirewallMark"),
+        Some("0x10")
+    );
+
+    Ok(())
+}
+
+/// Comments and additional values of keys we manage survive when we rewrite the config.
+#[rstest]
+#[tokio::test]
+async fn preserves_comments_and_additional_values(tmpdir: TempDir) -> Result<()> {
+    let keepalive_policy = KeepalivePolicy {
+        default: 25,
+        own: None,
+    };
+    let peer = WgPeer::new(
+        Privkey::generate().pubkey(),
+        "192.0.2.1:51820",
+        "10.0.0.2".parse()?,
+    );
+    let mut config = NetworkdConfiguration::new(
+        Privkey::generate(),
+        "10.0.0.7".parse()?,
+        "10.0.0.0/24".parse()?,
+        51820,
+        "wg0",
+        LinkOptions::default(),
+        HashSet::from([peer]),
+    )?;
+    config.write_config(&tmpdir, keepalive_policy).await?;
+
+    // An admin documents their changes and adds another address to the interface.
+    let network_file = tmpdir.join("wg0.network");
+    let network = std::fs::read_to_string(&network_file)?.replace(
+        "Address=10.0.0.7/24\n",
+        "Address=10.0.0.7/24\n# Used by the monitoring\nAddress=192.168.0.1/24\n",
+    );
+    std::fs::write(&network_file, &network)?;
+    let netdev_file = tmpdir.join("wg0.netdev");
+    let netdev = format!(
+        "# Don't touch the keys\n{}",
+        std::fs::read_to_string(&netdev_file)?
+    );
+    std::fs::write(&netdev_file, &netdev)?;
+
+    // Nothing changes as long as our config stays the same.
+    assert!(!config.write_config(&tmpdir, keepalive_policy).await?);
+    assert_eq!(std::fs::read_to_string(&network_file)?, network);
+    assert_eq!(std::fs::read_to_string(&netdev_file)?, netdev);
+
+    // A new address of our own only replaces the first address.
+    config.migrate("10.0.0.8".parse()?, "10.0.0.0/24".parse()?, 51820)?;
+    config.peers.clear();
+    assert!(config.write_config(&tmpdir, keepalive_policy).await?);
+    assert_eq!(
+        std::fs::read_to_string(&network_file)?,
+        network.replace("Address=10.0.0.7/24", "Address=10.0.0.8/24")
+    );
+    let netdev_ini = ini::Ini::load_from_file(&netdev_file)?;
+    assert_eq!(netdev_ini.section_all(Some("WireGuardPeer")).count(), 0);
+    assert!(std::fs::read_to_string(&netdev_file)?.starts_with("# Don't touch the keys\n"));
+
+    let read = NetworkdConfiguration::from_config(&tmpdir, "wg0").await?;
+    assert_eq!(read.wg_address, "10.0.0.8/24".parse::<IpNet>()?);
+
+    Ok(())
+}
@@ -1,11 +1,12 @@
 use std::{
+    net::{IpAddr, SocketAddr},
     path::Path,
     process::Stdio,
     time::{Duration, Instant},
 };
 
 use anyhow::Result;
-use tokio::{process::Command, time::sleep};
+use tokio::{net::UdpSocket, process::Command, task::JoinHandle, time::sleep};
 
 /// Wait a few seconds for the files to become available
 pub async fn wait_for_files(files: Vec<&Path>) {
@@ -72,6 +73,16 @@ impl WiresmithContainer {
             .await
             .expect("Error while waiting for systemd container");
 
+        // Tests that let wiresmith detect its endpoint pass their own endpoint option.
+        let endpoint_args = if args
+            .iter()
+            .any(|x| ["--endpoint-interface", "--endpoint-stun"].contains(x))
+        {
+            vec![]
+        } else {
+            vec!["--endpoint-address", &container_name]
+        };
+
         // Lastly, start wiresmith itself.
         Command::new("podman")
             .arg("exec")
@@ -82,7 +93,7 @@ impl WiresmithContainer {
                 &format!("http://consul-{consul_port}:{consul_port}"),
             ])
             .args(["--network", network])
-            .args(["--endpoint-address", &container_name])
+            .args(endpoint_args)
             .args(args)
             // To diagnose issues, it's sometimes helpful to comment out the following line so that
             // we can see log output from the wiresmith instances inside the containers.
@@ -138,3 +149,53 @@ async fn wait_for_systemd(container_name: &str) -> Result<()> {
         }
     }
 }
+
+/// Minimal STUN server which answers every Binding Request with a fixed mapped address
+///
+/// This allows us to pretend that we're behind a NAT.
+pub struct StunResponder {
+    pub address: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl StunResponder {
+    pub async fn new(mapped_address: SocketAddr) -> Self {
+        let socket = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .expect("Couldn't bind STUN responder");
+        let address = socket.local_addr().unwrap();
+
+        let task = tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                if len < 20 {
+                    continue;
+                }
+                let magic_cookie = &buf[4..8];
+                let transaction_id = &buf[8..20];
+
+                // Binding Success Response with a single XOR-MAPPED-ADDRESS attribute.
+                let mut response = vec![0x01, 0x01, 0x00, 0x0c];
+                response.extend_from_slice(magic_cookie);
+                response.extend_from_slice(transaction_id);
+                response.extend_from_slice(&[0x00, 0x20, 0x00, 0x08, 0x00, 0x01]);
+                let port = mapped_address.port() ^ u16::from_be_bytes([0x21, 0x12]);
+                response.extend_from_slice(&port.to_be_bytes());
+                let IpAddr::V4(ip) = mapped_address.ip() else {
+                    panic!("STUN responder only supports IPv4");
+                };
+                response.extend(ip.octets().iter().zip(magic_cookie).map(|(x, k)| x ^ k));
+
+                socket.send_to(&response, from).await.unwrap();
+            }
+        });
+
+        Self { address, task }
+    }
+}
+
+impl Drop for StunResponder {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}